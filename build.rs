@@ -5,13 +5,77 @@ pub struct Config {
     #[default("")]
     wifi_psk: &'static str,
     #[default("")]
+    waveplus_serials: &'static str,
+    #[default("")]
     waveplus_serial: &'static str,
     #[default(30)]
     read_interval: u16,
+    #[default(60)]
+    radon_interval: u32,
+    #[default(0)]
+    radon_offset: u32,
+    #[default(100)]
+    scan_interval: u16,
+    #[default(99)]
+    scan_window: u16,
+    #[default(10000)]
+    scan_timeout: u32,
+    #[default(0)]
+    min_read_interval: u32,
+    #[default(256)]
+    queue_capacity: u16,
+    #[default(10)]
+    batch_size: u16,
+    #[default(8192)]
+    batch_max_bytes: u32,
     #[default("")]
     server: &'static str,
+    #[default("json")]
+    output_format: &'static str,
+    #[default("http,mqtt")]
+    sinks: &'static str,
+    #[default("")]
+    influx_org: &'static str,
+    #[default("")]
+    influx_bucket: &'static str,
+    #[default("")]
+    influx_token: &'static str,
+    #[default("none")]
+    auth_type: &'static str,
+    #[default("")]
+    auth_token: &'static str,
+    #[default("")]
+    auth_username: &'static str,
+    #[default("")]
+    auth_password: &'static str,
+    #[default("")]
+    http_headers: &'static str,
+    #[default("")]
+    signing_secret: &'static str,
     #[default("pool.ntp.org")]
     ntp_server: &'static str,
+    #[default(3)]
+    http_retries: u32,
+    #[default(1000)]
+    http_retry_delay: u32,
+    #[default(80)]
+    http_port: u16,
+    #[default("")]
+    ota_url: &'static str,
+    #[default(86400)]
+    ota_interval: u32,
+    #[default("")]
+    mqtt_url: &'static str,
+    #[default("waveplus-reader")]
+    mqtt_client_id: &'static str,
+    #[default("")]
+    mqtt_username: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    #[default("waveplus")]
+    mqtt_prefix: &'static str,
+    #[default("homeassistant")]
+    mqtt_discovery_prefix: &'static str,
 }
 
 fn main() {
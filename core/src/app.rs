@@ -0,0 +1,14 @@
+pub mod auth;
+pub mod effects;
+pub mod fingerprint;
+pub mod influx;
+pub mod metrics;
+pub mod payload;
+pub mod queue;
+pub mod radon;
+pub mod retry;
+pub mod signing;
+pub mod sink;
+pub mod state;
+pub mod topics;
+pub mod version;
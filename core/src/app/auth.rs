@@ -0,0 +1,68 @@
+use anyhow::{bail, Result};
+use base64::Engine;
+use std::fmt;
+
+/// Credentials sent with every upload.
+#[derive(Clone, PartialEq)]
+pub enum Auth {
+    None,
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+impl Auth {
+    /// Build from the `auth_type`, `auth_token`, `auth_username` and
+    /// `auth_password` settings.
+    pub fn new(kind: &str, token: &str, username: &str, password: &str) -> Result<Auth> {
+        match kind {
+            "" | "none" => Ok(Auth::None),
+            "bearer" if token.is_empty() => bail!("auth_token is required for bearer auth"),
+            "bearer" => Ok(Auth::Bearer(token.to_string())),
+            "basic" if username.is_empty() => bail!("auth_username is required for basic auth"),
+            "basic" => Ok(Auth::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => bail!("auth_type must be none, bearer or basic: {:?}", kind),
+        }
+    }
+
+    /// The value of the `authorization` header, if any.
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Auth::None => None,
+            Auth::Bearer(token) => Some(format!("Bearer {}", token)),
+            Auth::Basic { username, password } => Some(format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{}:{}", username, password))
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::None => write!(f, "None"),
+            Auth::Bearer(_) => write!(f, "Bearer(<redacted>)"),
+            Auth::Basic { username, .. } => write!(f, "Basic({}, <redacted>)", username),
+        }
+    }
+}
+
+/// Parse static headers given one per line as `Name: value`. Values are
+/// left out of errors as they usually hold API keys.
+pub fn parse_headers(headers: &str) -> Result<Vec<(String, String)>> {
+    headers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((name.trim().to_lowercase(), value.trim().to_string()))
+            }
+            _ => bail!("http_headers must be one \"Name: value\" per line"),
+        })
+        .collect()
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use time::OffsetDateTime;
+
+use crate::app::retry::SendError;
+use crate::app::sink::SinkKind;
+use crate::app::state::State;
+use crate::waveplus::address::Address;
+use crate::waveplus::measurement::Measurement;
+
+/// Access to the Wave Plus over BLE.
+pub trait Sensor {
+    fn scan(&mut self, serials: &[u32]) -> Result<Vec<(u32, Address)>>;
+
+    fn read(&mut self, serial: u32, address: &Address, include_radon: bool) -> Result<Measurement>;
+}
+
+/// Access to the network used to upload measurements.
+pub trait Network {
+    fn send(&mut self, body: &str) -> Result<(), SendError>;
+
+    /// Whether the link to the access point is up.
+    fn is_connected(&self) -> Result<bool>;
+
+    fn disconnect(&mut self) -> Result<()>;
+
+    fn wait_for_connected(&mut self) -> Result<()>;
+}
+
+/// Access to wall clock time and sleeping.
+pub trait Clock {
+    fn now(&self) -> Result<OffsetDateTime>;
+
+    fn delay_ms(&self, ms: u32);
+}
+
+/// State that persists across reboots.
+pub trait Store {
+    /// When each of the given devices last reported radon.
+    fn load_radon_reports(&self, serials: &[u32]) -> Result<Vec<(u32, OffsetDateTime)>>;
+
+    fn save_radon_report(&mut self, serial: u32, at: OffsetDateTime) -> Result<()>;
+
+    /// The addresses resolved by earlier scans, for the given serials.
+    fn load_addresses(&self, serials: &[u32]) -> Result<Vec<(u32, Address)>>;
+
+    fn save_address(&mut self, serial: u32, address: &Address) -> Result<()>;
+
+    fn remove_address(&mut self, serial: u32) -> Result<()>;
+}
+
+/// An output the measurements in each round are sent to, such as the
+/// upload server or an MQTT broker.
+pub trait Sink {
+    fn kind(&self) -> SinkKind;
+
+    fn send(&mut self, state: &State) -> Result<()>;
+}
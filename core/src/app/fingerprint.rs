@@ -0,0 +1,84 @@
+//! Parsing of the pinned server certificate fingerprint.
+
+use anyhow::{bail, Result};
+
+/// Parse a SHA-256 fingerprint given as 64 hex digits, optionally separated
+/// by colons and prefixed as printed by `openssl x509 -fingerprint -sha256`.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<Option<[u8; 32]>> {
+    let fingerprint = fingerprint.rsplit('=').next().unwrap_or_default();
+    let digits: Vec<u8> = fingerprint
+        .trim()
+        .bytes()
+        .filter(|digit| *digit != b':')
+        .collect();
+    if digits.is_empty() {
+        return Ok(None);
+    }
+    if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        bail!("fingerprint must be 64 hex digits");
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair)?, 16)?;
+    }
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "0123456789abcdef0123456789ABCDEF0123456789abcdef0123456789ABCDEF";
+    const BYTES: [u8; 32] = [
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+        0xcd, 0xef,
+    ];
+
+    fn with_colons(hex: &str) -> String {
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    #[test]
+    fn parses_plain_hex() {
+        assert_eq!(parse_fingerprint(HEX).unwrap(), Some(BYTES));
+        assert_eq!(
+            parse_fingerprint(&format!("  {}\n", HEX)).unwrap(),
+            Some(BYTES)
+        );
+    }
+
+    #[test]
+    fn parses_colon_separated_hex() {
+        assert_eq!(parse_fingerprint(&with_colons(HEX)).unwrap(), Some(BYTES));
+        let openssl = format!("sha256 Fingerprint={}", with_colons(HEX));
+        assert_eq!(parse_fingerprint(&openssl).unwrap(), Some(BYTES));
+    }
+
+    #[test]
+    fn empty_is_no_fingerprint() {
+        assert_eq!(parse_fingerprint("").unwrap(), None);
+        assert_eq!(parse_fingerprint("  ").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        assert!(parse_fingerprint(&HEX[..62]).is_err());
+        assert!(parse_fingerprint(&format!("{}00", HEX)).is_err());
+        assert!(parse_fingerprint(&with_colons(&HEX[..62])).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_hex() {
+        let invalid = format!("{}zz", &HEX[..62]);
+        assert_eq!(
+            parse_fingerprint(&invalid).unwrap_err().to_string(),
+            "fingerprint must be 64 hex digits"
+        );
+        assert!(parse_fingerprint(&format!("+{}", &HEX[..63])).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::app::sink::SinkKind;
+use crate::app::state::{Device, Errors};
+use crate::waveplus::measurement::Measurement;
+
+pub struct InfluxSettings<'a> {
+    pub org: &'a str,
+    pub bucket: &'a str,
+    pub token: &'a str,
+}
+
+/// Escape a tag value, commas, equals signs and spaces are significant in
+/// line protocol.
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Percent-encode a query string parameter.
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// The InfluxDB v2 write endpoint on `server` for nanosecond timestamps.
+pub fn write_url(server: &str, settings: &InfluxSettings) -> String {
+    format!(
+        "{}/api/v2/write?org={}&bucket={}&precision=ns",
+        server.trim_end_matches('/'),
+        encode_query(settings.org),
+        encode_query(settings.bucket),
+    )
+}
+
+/// A `waveplus` line tagged with the serial and address, timestamped with
+/// the time the measurement was read.
+pub fn measurement_line(measurement: &Measurement) -> String {
+    let metadata = &measurement.metadata;
+    let fields: Vec<String> = measurement
+        .fields()
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    format!(
+        "waveplus,serial={},model={},address={} {} {}",
+        metadata.serial_number,
+        escape_tag(metadata.model.name()),
+        escape_tag(&metadata.address.to_string()),
+        fields.join(","),
+        metadata.timestamp.unix_timestamp_nanos(),
+    )
+}
+
+/// Error counter lines, left for the server to timestamp.
+pub fn errors_lines(errors: &Errors, devices: &[Device], firmware: &str) -> Vec<String> {
+    let mut lines = vec![format!(
+        "waveplus_errors wifi_disconnects={}i,ble_disconnects={}i,ble_scan_failures={}i,http_errors={}i,firmware=\"{}\"",
+        errors.wifi_disconnects,
+        errors.ble_disconnects,
+        errors.ble_scan_failures,
+        errors.http_errors,
+        firmware,
+    )];
+    for kind in SinkKind::ALL {
+        let counters = errors.sinks.get(kind);
+        lines.push(format!(
+            "waveplus_sink_errors,sink={} sent={}i,failed={}i",
+            kind, counters.sent, counters.failed,
+        ));
+    }
+    for device in devices {
+        lines.push(format!(
+            "waveplus_device_errors,serial={} ble_disconnects={}i,not_found={}i,unsupported_payloads={}i",
+            device.serial,
+            device.errors.ble_disconnects,
+            device.errors.not_found,
+            device.errors.unsupported_payloads,
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::radon::RadonSchedule;
+    use crate::app::state::State;
+    use crate::waveplus::address::Address;
+    use crate::waveplus::model::Model;
+    use time::OffsetDateTime;
+
+    const SERIAL: u32 = 2930_123456;
+
+    fn measurement() -> Measurement {
+        let packet = [
+            1, 90, 51, 3, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        Measurement::logged(
+            SERIAL,
+            Model::WavePlus,
+            Address {
+                bytes: [0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+                kind: 0,
+            },
+            Model::WavePlus.decode(&packet).unwrap(),
+            OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn escapes_tag_values() {
+        assert_eq!(escape_tag("Wave Plus"), r"Wave\ Plus");
+        assert_eq!(escape_tag("a,b=c\\d"), r"a\,b\=c\\d");
+        assert_eq!(escape_tag("01:02:03"), "01:02:03");
+    }
+
+    #[test]
+    fn measurement_line_is_tagged_and_timestamped_in_nanoseconds() {
+        let line = measurement_line(&measurement());
+        assert!(
+            line.starts_with(
+                r"waveplus,serial=2930123456,model=Wave\ Plus,address=01:02:03:04:05:06 humidity=45,"
+            ),
+            "{}",
+            line
+        );
+        assert!(line.ends_with(" 1700000000000000000"), "{}", line);
+        assert!(line.contains(",co2=600,"), "{}", line);
+        // Fields and timestamp are the only unescaped spaces.
+        assert_eq!(line.matches(' ').count() - line.matches(r"\ ").count(), 2);
+    }
+
+    #[test]
+    fn percent_encodes_the_write_url() {
+        let settings = InfluxSettings {
+            org: "my org&co",
+            bucket: "air/quality",
+            token: "",
+        };
+        assert_eq!(
+            write_url("http://influx:8086/", &settings),
+            "http://influx:8086/api/v2/write?org=my%20org%26co&bucket=air%2Fquality&precision=ns"
+        );
+        let settings = InfluxSettings {
+            org: "home",
+            bucket: "waveplus",
+            token: "",
+        };
+        assert_eq!(
+            write_url("https://influx.example.com", &settings),
+            "https://influx.example.com/api/v2/write?org=home&bucket=waveplus&precision=ns"
+        );
+    }
+
+    #[test]
+    fn errors_lines_report_every_counter() {
+        let errors = Errors {
+            wifi_disconnects: 1,
+            http_errors: 2,
+            ..Errors::default()
+        };
+        let devices = State::new(&[SERIAL], RadonSchedule::default()).devices;
+        let lines = errors_lines(&errors, &devices, "0.1.0");
+        assert_eq!(
+            lines[0],
+            "waveplus_errors wifi_disconnects=1i,ble_disconnects=0i,ble_scan_failures=0i,http_errors=2i,firmware=\"0.1.0\""
+        );
+        assert_eq!(lines.len(), 1 + SinkKind::ALL.len() + 1);
+        assert!(lines[1].starts_with("waveplus_sink_errors,sink="));
+        assert_eq!(
+            lines[lines.len() - 1],
+            "waveplus_device_errors,serial=2930123456 ble_disconnects=0i,not_found=0i,unsupported_payloads=0i"
+        );
+    }
+}
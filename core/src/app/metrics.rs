@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::app::sink::SinkKind;
+use crate::app::state::{Errors, State};
+use crate::waveplus::measurement::Measurement;
+
+/// Gauges exported for each measurement field, with their help text.
+const GAUGES: [(&str, &str); 18] = [
+    ("humidity", "Relative humidity in percent"),
+    ("radon_short", "Short term radon average in Bq/m3"),
+    ("radon_long", "Long term radon average in Bq/m3"),
+    ("temperature", "Temperature in degrees Celsius"),
+    ("pressure", "Atmospheric pressure in hPa"),
+    ("co2", "CO2 level in ppm"),
+    ("voc", "VOC level in ppb"),
+    ("pm1", "PM1 concentration in ug/m3"),
+    ("pm2_5", "PM2.5 concentration in ug/m3"),
+    ("light", "Ambient light level in percent"),
+    ("waves", "Waves activity counter"),
+    ("battery", "Estimated battery level in percent"),
+    ("battery_voltage", "Battery voltage in V"),
+    ("battery_low", "Whether the battery is low"),
+    (
+        "connection_rssi",
+        "BLE signal strength of the connection in dBm",
+    ),
+    ("connect_ms", "GATT connect time in ms"),
+    ("discover_ms", "GATT service discovery time in ms"),
+    ("read_ms", "GATT read time in ms"),
+];
+
+/// The latest values exposed on the `/metrics` endpoint.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    started: Instant,
+    last_read: Option<Instant>,
+    measurements: BTreeMap<u32, Measurement>,
+    errors: Errors,
+}
+
+impl Metrics {
+    pub fn new(started: Instant) -> Self {
+        Metrics {
+            started,
+            last_read: None,
+            measurements: BTreeMap::new(),
+            errors: Errors::default(),
+        }
+    }
+
+    /// Record the error counters and any fresh measurements in `state`.
+    pub fn update(&mut self, state: &State, now: Instant) {
+        self.errors = state.errors;
+        if state.measurements.is_empty() {
+            return;
+        }
+        for measurement in state.measurements.iter() {
+            let serial = measurement.metadata.serial_number;
+            let mut measurement = *measurement;
+            // Radon is only read hourly, keep exporting the last value.
+            if let Some(previous) = self.measurements.get(&serial) {
+                measurement.data.keep_radon(&previous.data);
+            }
+            self.measurements.insert(serial, measurement);
+        }
+        self.last_read = Some(now);
+    }
+
+    /// Render in the Prometheus text exposition format.
+    pub fn render(&self, now: Instant) -> String {
+        let mut out = String::new();
+
+        for (field, help) in GAUGES {
+            let name = format!("waveplus_{}", field);
+            let samples: Vec<(&Measurement, f64)> = self
+                .measurements
+                .values()
+                .filter_map(|measurement| {
+                    measurement
+                        .fields()
+                        .into_iter()
+                        .find(|(key, _)| *key == field)
+                        .map(|(_, value)| (measurement, value))
+                })
+                .collect();
+            if samples.is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (measurement, value) in samples {
+                let _ = writeln!(
+                    out,
+                    "{}{{serial=\"{}\",model=\"{}\",address=\"{}\"}} {}",
+                    name,
+                    measurement.metadata.serial_number,
+                    escape_label(measurement.metadata.model.name()),
+                    escape_label(&measurement.metadata.address.to_string()),
+                    value
+                );
+            }
+        }
+
+        let counters = [
+            (
+                "wifi_disconnects",
+                "Wi-Fi reconnections",
+                self.errors.wifi_disconnects,
+            ),
+            (
+                "ble_disconnects",
+                "Failed BLE reads, each followed by a rescan",
+                self.errors.ble_disconnects,
+            ),
+            (
+                "ble_scan_failures",
+                "BLE scans that failed to run",
+                self.errors.ble_scan_failures,
+            ),
+            ("http_errors", "Failed uploads", self.errors.http_errors),
+        ];
+        for (counter, help, value) in counters {
+            let name = format!("waveplus_{}_total", counter);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP waveplus_sink_sends_total Measurement rounds sent to each sink"
+        );
+        let _ = writeln!(out, "# TYPE waveplus_sink_sends_total counter");
+        for kind in SinkKind::ALL {
+            let counters = self.errors.sinks.get(kind);
+            for (result, value) in [("sent", counters.sent), ("failed", counters.failed)] {
+                let _ = writeln!(
+                    out,
+                    "waveplus_sink_sends_total{{sink=\"{}\",result=\"{}\"}} {}",
+                    kind, result, value
+                );
+            }
+        }
+
+        let uptime = now.saturating_duration_since(self.started);
+        let _ = writeln!(out, "# HELP waveplus_uptime_seconds Time since boot");
+        let _ = writeln!(out, "# TYPE waveplus_uptime_seconds gauge");
+        let _ = writeln!(out, "waveplus_uptime_seconds {}", uptime.as_secs());
+
+        if let Some(last_read) = self.last_read {
+            let age: Duration = now.saturating_duration_since(last_read);
+            let _ = writeln!(
+                out,
+                "# HELP waveplus_last_read_age_seconds Time since the last successful read"
+            );
+            let _ = writeln!(out, "# TYPE waveplus_last_read_age_seconds gauge");
+            let _ = writeln!(out, "waveplus_last_read_age_seconds {}", age.as_secs());
+        }
+
+        out
+    }
+}
+
+/// Escape a label value as the exposition format requires.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::radon::RadonSchedule;
+    use crate::waveplus::address::Address;
+    use crate::waveplus::model::Model;
+    use time::OffsetDateTime;
+
+    const SERIAL: u32 = 2930_123456;
+
+    fn metrics() -> (Metrics, Instant) {
+        let started = Instant::now();
+        let packet = [
+            1, 90, 51, 3, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        let measurement = Measurement::logged(
+            SERIAL,
+            Model::WavePlus,
+            Address {
+                bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+                kind: 0,
+            },
+            Model::WavePlus.decode(&packet).unwrap(),
+            OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        )
+        .unwrap();
+        let state =
+            State::new(&[SERIAL], RadonSchedule::default()).with_measurements(vec![measurement]);
+        let mut metrics = Metrics::new(started);
+        metrics.update(&state, started + Duration::from_secs(5));
+        (metrics, started + Duration::from_secs(65))
+    }
+
+    #[test]
+    fn renders_gauges_with_help_and_type() {
+        let (metrics, now) = metrics();
+        let out = metrics.render(now);
+        assert!(out.contains(
+            "# HELP waveplus_humidity Relative humidity in percent\n\
+             # TYPE waveplus_humidity gauge\n\
+             waveplus_humidity{serial=\"2930123456\",model=\"Wave Plus\",address=\"06:05:04:03:02:01\"} 45\n"
+        ));
+        assert!(out
+            .contains("# TYPE waveplus_http_errors_total counter\nwaveplus_http_errors_total 0\n"));
+        assert!(out.contains("waveplus_sink_sends_total{sink=\"mqtt\",result=\"failed\"} 0\n"));
+        assert!(out.contains("waveplus_uptime_seconds 65\n"));
+        assert!(out.contains("waveplus_last_read_age_seconds 60\n"));
+    }
+
+    #[test]
+    fn leaves_out_missing_fields() {
+        let (metrics, now) = metrics();
+        let out = metrics.render(now);
+        // A Wave Plus has no particulate sensor, and no battery or link
+        // diagnostics were read.
+        for field in ["pm1", "pm2_5", "battery", "connection_rssi"] {
+            assert!(
+                !out.contains(&format!("waveplus_{} ", field))
+                    && !out.contains(&format!("waveplus_{}{{", field)),
+                "{} is rendered",
+                field
+            );
+        }
+
+        let out = Metrics::new(now).render(now);
+        assert!(!out.contains("waveplus_humidity"));
+        assert!(!out.contains("waveplus_last_read_age_seconds"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("Wave Plus"), "Wave Plus");
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
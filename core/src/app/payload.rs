@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Error, Result};
+use log::*;
+use std::str::FromStr;
+
+use crate::app::influx;
+use crate::app::state::State;
+use crate::waveplus::measurement::Measurement;
+
+/// Encoding of the uploaded measurements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    Json,
+    Influx,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(PayloadFormat::Json),
+            "influx" => Ok(PayloadFormat::Influx),
+            _ => Err(anyhow!("Unknown output format {:?}", format)),
+        }
+    }
+}
+
+impl PayloadFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "application/json",
+            PayloadFormat::Influx => "text/plain; charset=utf-8",
+        }
+    }
+
+    /// The payload for the measurements in `state`.
+    pub fn encode(&self, state: &State) -> Result<String> {
+        match self {
+            PayloadFormat::Json => Ok(serde_json::to_string(state)?),
+            PayloadFormat::Influx => {
+                let mut lines: Vec<String> = state
+                    .measurements
+                    .iter()
+                    .map(influx::measurement_line)
+                    .collect();
+                lines.extend(influx::errors_lines(
+                    &state.errors,
+                    &state.devices,
+                    state.firmware,
+                ));
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    /// Build the payload for as many of the queued `entries` as fit in
+    /// `max_bytes`, returning it with the number of entries it covers.  At
+    /// least one entry is always included so that the queue can drain.
+    pub fn encode_batch(
+        &self,
+        state: &State,
+        entries: &[Vec<u8>],
+        max_bytes: usize,
+    ) -> (String, usize) {
+        let mut count = entries.len();
+        loop {
+            let payload = self.batch(state, &entries[..count]);
+            if payload.len() <= max_bytes || count <= 1 {
+                return (payload, count);
+            }
+            count -= 1;
+        }
+    }
+
+    fn batch(&self, state: &State, entries: &[Vec<u8>]) -> String {
+        let measurements: Vec<Measurement> = entries
+            .iter()
+            .filter_map(|entry| match Measurement::from_queue_entry(entry) {
+                Ok(measurement) => Some(measurement),
+                Err(err) => {
+                    error!("Dropping unreadable queued measurement: {:?}", err);
+                    None
+                }
+            })
+            .collect();
+        match self {
+            PayloadFormat::Json => state.queued_payload(&measurements).to_string(),
+            PayloadFormat::Influx => measurements
+                .iter()
+                .map(influx::measurement_line)
+                .chain(influx::errors_lines(
+                    &state.errors,
+                    &state.devices,
+                    state.firmware,
+                ))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
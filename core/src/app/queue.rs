@@ -0,0 +1,212 @@
+use anyhow::Result;
+
+/// Key-value storage backing a [`MeasurementQueue`], such as an NVS
+/// namespace.
+pub trait QueueStorage {
+    fn get_u32(&self, key: &str) -> Result<Option<u32>>;
+
+    fn set_u32(&mut self, key: &str, value: u32) -> Result<()>;
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()>;
+
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+const HEAD_KEY: &str = "head";
+const TAIL_KEY: &str = "tail";
+const CAPACITY_KEY: &str = "capacity";
+
+/// Bounded FIFO of serialized measurements that persists across reboots.
+///
+/// `head` and `tail` are monotonically increasing sequence numbers, the
+/// entry for a sequence number is stored in slot `seq % capacity`.  When
+/// the queue is full the oldest entry is dropped.
+///
+/// As the slots depend on it, the capacity is stored with the queue and the
+/// queue is emptied when it changes.
+pub struct MeasurementQueue<S: QueueStorage> {
+    storage: S,
+    capacity: u32,
+    head: u32,
+    tail: u32,
+}
+
+impl<S: QueueStorage> MeasurementQueue<S> {
+    pub fn new(storage: S, capacity: u32) -> Result<Self> {
+        let capacity = capacity.max(1);
+        let head = storage.get_u32(HEAD_KEY)?.unwrap_or(0);
+        let tail = storage.get_u32(TAIL_KEY)?.unwrap_or(0);
+        // Queues written before the capacity was stored used the configured
+        // one.
+        let stored = storage.get_u32(CAPACITY_KEY)?;
+        let mut queue = MeasurementQueue {
+            storage,
+            capacity: stored.unwrap_or(capacity).max(1),
+            head,
+            tail: tail.max(head),
+        };
+        if queue.len() > queue.capacity as usize {
+            let head = queue.tail.wrapping_sub(queue.capacity);
+            queue.set_head(head)?;
+        }
+        if queue.capacity != capacity {
+            if !queue.is_empty() {
+                log::warn!(
+                    "Queue capacity changed from {} to {}, dropping {} queued measurements",
+                    queue.capacity,
+                    capacity,
+                    queue.len()
+                );
+            }
+            queue.pop_many(queue.len())?;
+            queue.capacity = capacity;
+        }
+        if stored != Some(capacity) {
+            queue.storage.set_u32(CAPACITY_KEY, capacity)?;
+        }
+        Ok(queue)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, entry: &[u8]) -> Result<()> {
+        if self.len() >= self.capacity as usize {
+            log::warn!("Measurement queue full, dropping oldest entry");
+            self.set_head(self.head.wrapping_add(1))?;
+        }
+        let key = self.key(self.tail);
+        self.storage.set_blob(&key, entry)?;
+        self.tail = self.tail.wrapping_add(1);
+        self.storage.set_u32(TAIL_KEY, self.tail)
+    }
+
+    /// Up to `count` of the oldest entries, without removing them from the
+    /// queue.
+    pub fn peek_batch(&self, count: usize) -> Result<Vec<Vec<u8>>> {
+        let mut entries = Vec::new();
+        for offset in 0..count.min(self.len()) {
+            let seq = self.head.wrapping_add(offset as u32);
+            // A missing entry is returned empty so that it is dropped along
+            // with the rest of the batch.
+            entries.push(self.storage.get_blob(&self.key(seq))?.unwrap_or_default());
+        }
+        Ok(entries)
+    }
+
+    /// Remove the oldest entry from the queue.
+    pub fn pop(&mut self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let key = self.key(self.head);
+        self.set_head(self.head.wrapping_add(1))?;
+        self.storage.remove(&key)
+    }
+
+    /// Remove up to `count` of the oldest entries from the queue.
+    pub fn pop_many(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            self.pop()?;
+        }
+        Ok(())
+    }
+
+    fn set_head(&mut self, head: u32) -> Result<()> {
+        self.head = head;
+        self.storage.set_u32(HEAD_KEY, self.head)
+    }
+
+    fn key(&self, seq: u32) -> String {
+        format!("m{}", seq % self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        u32s: HashMap<String, u32>,
+        blobs: HashMap<String, Vec<u8>>,
+    }
+
+    impl QueueStorage for MemoryStorage {
+        fn get_u32(&self, key: &str) -> Result<Option<u32>> {
+            Ok(self.u32s.get(key).copied())
+        }
+
+        fn set_u32(&mut self, key: &str, value: u32) -> Result<()> {
+            self.u32s.insert(key.to_string(), value);
+            Ok(())
+        }
+
+        fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.blobs.get(key).cloned())
+        }
+
+        fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+            self.blobs.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<()> {
+            self.blobs.remove(key);
+            Ok(())
+        }
+    }
+
+    fn queue_of(capacity: u32, entries: &[u8]) -> MeasurementQueue<MemoryStorage> {
+        let mut queue = MeasurementQueue::new(MemoryStorage::default(), capacity).unwrap();
+        for entry in entries {
+            queue.push(&[*entry]).unwrap();
+        }
+        queue
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let mut queue = queue_of(3, &[1, 2, 3, 4]);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.peek_batch(5).unwrap(), [[2], [3], [4]]);
+        queue.pop_many(2).unwrap();
+        assert_eq!(queue.peek_batch(5).unwrap(), [[4]]);
+    }
+
+    #[test]
+    fn survives_reopening() {
+        let queue = queue_of(3, &[1, 2, 3, 4]);
+        let queue = MeasurementQueue::new(queue.storage, 3).unwrap();
+        assert_eq!(queue.peek_batch(5).unwrap(), [[2], [3], [4]]);
+    }
+
+    #[test]
+    fn changed_capacity_empties_queue() {
+        let queue = queue_of(3, &[1, 2, 3, 4]);
+        let mut queue = MeasurementQueue::new(queue.storage, 5).unwrap();
+        assert!(queue.is_empty());
+        assert!(queue.storage.blobs.is_empty());
+        assert_eq!(queue.storage.u32s[CAPACITY_KEY], 5);
+
+        queue.push(&[5]).unwrap();
+        let queue = MeasurementQueue::new(queue.storage, 5).unwrap();
+        assert_eq!(queue.peek_batch(5).unwrap(), [[5]]);
+    }
+
+    #[test]
+    fn queue_without_capacity_keeps_entries() {
+        let mut queue = queue_of(3, &[1, 2]);
+        queue.storage.u32s.remove(CAPACITY_KEY);
+        let queue = MeasurementQueue::new(queue.storage, 3).unwrap();
+        assert_eq!(queue.peek_batch(5).unwrap(), [[1], [2]]);
+    }
+}
@@ -0,0 +1,109 @@
+use time::OffsetDateTime;
+
+/// When to include radon in a measurement.
+///
+/// The Wave Plus only updates its radon averages once an hour, so reporting
+/// them on every read would repeat the same value.  Time is divided into
+/// slots of `interval_minutes`, starting `offset_minutes` past the hour to
+/// line up with the sensor's own update, and radon is reported once per
+/// slot.  Slots are computed from UTC timestamps so that midnight and DST
+/// changes have no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RadonSchedule {
+    pub interval_minutes: u32,
+    pub offset_minutes: u32,
+}
+
+impl Default for RadonSchedule {
+    fn default() -> Self {
+        RadonSchedule {
+            interval_minutes: 60,
+            offset_minutes: 0,
+        }
+    }
+}
+
+impl RadonSchedule {
+    fn slot(&self, at: OffsetDateTime) -> i64 {
+        let minutes = at.unix_timestamp().div_euclid(60);
+        let interval = i64::from(self.interval_minutes.max(1));
+        (minutes - i64::from(self.offset_minutes)).div_euclid(interval)
+    }
+
+    /// Whether radon should be reported at `now` given it was last reported
+    /// at `last`.  If the clock has jumped back before `last` the schedule
+    /// restarts.
+    pub fn is_due(&self, last: Option<OffsetDateTime>, now: OffsetDateTime) -> bool {
+        match last {
+            None => true,
+            Some(last) if now < last => true,
+            Some(last) => self.slot(now) > self.slot(last),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::UtcOffset;
+
+    /// `timestamp` as seen in a zone `hours` ahead of UTC.
+    fn at(timestamp: i64, hours: i8) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp)
+            .unwrap()
+            .to_offset(UtcOffset::from_hms(hours, 0, 0).unwrap())
+    }
+
+    #[test]
+    fn due_once_per_hour() {
+        let schedule = RadonSchedule::default();
+        // 2024-01-01 10:05 and 10:55 UTC, then 11:00.
+        let last = at(1_704_103_500, 0);
+        assert!(schedule.is_due(None, last));
+        assert!(!schedule.is_due(Some(last), at(1_704_106_500, 0)));
+        assert!(schedule.is_due(Some(last), at(1_704_106_800, 0)));
+    }
+
+    #[test]
+    fn due_after_midnight() {
+        let schedule = RadonSchedule::default();
+        // 2024-01-01 23:30 and 2024-01-02 00:05 UTC.
+        assert!(schedule.is_due(Some(at(1_704_151_800, 0)), at(1_704_153_900, 0)));
+    }
+
+    #[test]
+    fn daylight_saving_changes_have_no_effect() {
+        let schedule = RadonSchedule::default();
+        // Spring forward: 01:30 CET then 03:05 CEST, 35 minutes later in
+        // the next hour.
+        assert!(schedule.is_due(Some(at(1_711_845_000, 1)), at(1_711_847_100, 2)));
+        // Fall back: 02:50 CEST then 02:10 CET, 20 minutes later in the
+        // next hour.
+        assert!(schedule.is_due(Some(at(1_729_990_200, 2)), at(1_729_991_400, 1)));
+        // 02:10 then 02:50 CEST, in the same hour.
+        assert!(!schedule.is_due(Some(at(1_729_987_800, 2)), at(1_729_990_200, 2)));
+    }
+
+    #[test]
+    fn clock_jumping_back_restarts_schedule() {
+        let schedule = RadonSchedule::default();
+        let last = at(1_704_103_500, 0);
+        assert!(schedule.is_due(Some(last), at(1_704_103_440, 0)));
+        // Jumping forward is a new slot like any other.
+        assert!(schedule.is_due(Some(last), at(1_735_689_600, 0)));
+    }
+
+    #[test]
+    fn slots_follow_interval_and_offset() {
+        // Every 30 minutes, at 10 and 40 past the hour.
+        let schedule = RadonSchedule {
+            interval_minutes: 30,
+            offset_minutes: 10,
+        };
+        // 10:05, 10:09, 10:10 and 10:39 UTC.
+        let last = at(1_704_103_500, 0);
+        assert!(!schedule.is_due(Some(last), at(1_704_103_740, 0)));
+        assert!(schedule.is_due(Some(last), at(1_704_103_800, 0)));
+        assert!(!schedule.is_due(Some(at(1_704_103_800, 0)), at(1_704_105_540, 0)));
+    }
+}
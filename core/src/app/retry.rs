@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::Duration;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// Why an upload failed, which decides whether it is worth retrying.
+#[derive(Debug)]
+pub enum SendError {
+    /// The request never got a response, e.g. DNS, TCP or TLS failures.
+    Transport(anyhow::Error),
+    /// 429 Too Many Requests, with the delay asked for by `Retry-After`.
+    TooManyRequests(Option<Duration>),
+    /// Any other 4xx, which retrying won't fix.
+    Client(u16),
+    /// A 5xx or other unexpected status.
+    Server(u16),
+}
+
+impl SendError {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, SendError::Client(_))
+    }
+
+    /// Whether the server refused the request itself, so that it will never
+    /// be accepted. Rejected credentials are not the request's fault and
+    /// may be fixed.
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, SendError::Client(status) if !matches!(status, 401 | 403 | 407))
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Transport(err) => write!(f, "Transport error: {:?}", err),
+            SendError::TooManyRequests(_) => write!(f, "Too many requests"),
+            SendError::Client(status) | SendError::Server(status) => {
+                write!(f, "Unexpected response code: {}", status)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<anyhow::Error> for SendError {
+    fn from(err: anyhow::Error) -> Self {
+        SendError::Transport(err)
+    }
+}
+
+/// How failed uploads are retried before giving up.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each one after it.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `attempt` (starting at 0) after
+    /// `err`, or `None` when it should not be retried. `jitter` is a
+    /// random value spreading the delay over its upper half.
+    pub fn delay(&self, attempt: u32, err: &SendError, jitter: u32) -> Option<Duration> {
+        if attempt >= self.retries || !err.is_retryable() {
+            return None;
+        }
+        if let SendError::TooManyRequests(Some(retry_after)) = err {
+            return Some((*retry_after).min(self.max_delay));
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        let half = delay / 2;
+        Some(half + half.mul_f64(f64::from(jitter) / f64::from(u32::MAX)))
+    }
+}
+
+/// The delay asked for by a `Retry-After` header at `now`, given either as
+/// a number of seconds or as an HTTP date.
+pub fn parse_retry_after(value: &str, now: OffsetDateTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = parse_http_date(value)?;
+    Some(Duration::try_from(at - now).unwrap_or(Duration::ZERO))
+}
+
+/// Parse a date in the IMF-fixdate format servers send, such as
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(value: &str) -> Option<OffsetDateTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let [_, day, month, year, time, "GMT"] = value.split_whitespace().collect::<Vec<_>>()[..]
+    else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|name| *name == month)?;
+    let month = Month::try_from(u8::try_from(month).ok()? + 1).ok()?;
+    let date = Date::from_calendar_date(year.parse().ok()?, month, day.parse().ok()?).ok()?;
+    let [hour, minute, second] = time.split(':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let time = Time::from_hms(
+        hour.parse().ok()?,
+        minute.parse().ok()?,
+        second.parse().ok()?,
+    )
+    .ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        retries: 3,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(4),
+    };
+
+    #[test]
+    fn delay_doubles_with_jitter_over_the_upper_half() {
+        let err = SendError::Server(503);
+        assert_eq!(POLICY.delay(0, &err, 0), Some(Duration::from_millis(500)));
+        assert_eq!(
+            POLICY.delay(0, &err, u32::MAX),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(POLICY.delay(1, &err, 0), Some(Duration::from_secs(1)));
+        assert_eq!(
+            POLICY.delay(2, &err, u32::MAX),
+            Some(Duration::from_secs(4))
+        );
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let policy = RetryPolicy {
+            retries: 20,
+            ..POLICY
+        };
+        let err = SendError::Server(500);
+        assert_eq!(
+            policy.delay(19, &err, u32::MAX),
+            Some(Duration::from_secs(4))
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let err = SendError::Transport(anyhow::anyhow!("timed out"));
+        assert!(POLICY.delay(2, &err, 0).is_some());
+        assert_eq!(POLICY.delay(3, &err, 0), None);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        assert_eq!(POLICY.delay(0, &SendError::Client(400), 0), None);
+        assert!(SendError::Client(422).is_rejection());
+        assert!(!SendError::Client(401).is_rejection());
+        assert!(!SendError::Server(500).is_rejection());
+    }
+
+    #[test]
+    fn delay_follows_retry_after() {
+        let err = SendError::TooManyRequests(Some(Duration::from_secs(2)));
+        assert_eq!(POLICY.delay(0, &err, 0), Some(Duration::from_secs(2)));
+        let err = SendError::TooManyRequests(Some(Duration::from_secs(60)));
+        assert_eq!(POLICY.delay(0, &err, 0), Some(Duration::from_secs(4)));
+        let err = SendError::TooManyRequests(None);
+        assert_eq!(POLICY.delay(0, &err, 0), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = PrimitiveDateTime::new(
+            Date::from_calendar_date(1994, Month::November, 6).unwrap(),
+            Time::from_hms(8, 48, 37).unwrap(),
+        )
+        .assume_utc();
+        let parse = |value| parse_retry_after(value, now);
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(parse("Sun, 06 Nov 1994 08:00:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse("soon"), None);
+    }
+}
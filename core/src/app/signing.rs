@@ -0,0 +1,124 @@
+//! HMAC-SHA256 signatures letting the server verify that an upload comes
+//! from a device holding the shared secret and has not been replayed.
+//!
+//! The signed message is the canonical string
+//!
+//! ```text
+//! <timestamp>\n<nonce>\n<body>
+//! ```
+//!
+//! where `timestamp` is the unix time in seconds in decimal, `nonce` is the
+//! value of the nonce header and `body` is the request body exactly as
+//! sent. The signature is the lowercase hex HMAC-SHA256 of that string,
+//! sent as `x-signature: sha256=<hex>` along with the
+//! `x-signature-timestamp` and `x-signature-nonce` headers. A server should
+//! recompute it over the raw body, reject timestamps outside a small window
+//! and nonces it has already seen within that window.
+//!
+//! This module performs no I/O so that it can be checked off-device.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const SIGNATURE_HEADER: &str = "x-signature";
+pub const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+pub const NONCE_HEADER: &str = "x-signature-nonce";
+
+/// The string that is signed for a request.
+pub fn canonical(timestamp: i64, nonce: &str, body: &str) -> String {
+    format!("{}\n{}\n{}", timestamp, nonce, body)
+}
+
+/// The lowercase hex HMAC-SHA256 of the canonical string.
+pub fn sign(secret: &[u8], timestamp: i64, nonce: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(canonical(timestamp, nonce, body).as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+/// A nonce from random bytes.
+pub fn nonce(random: &[u8]) -> String {
+    hex(random)
+}
+
+/// The headers to add to a request with `body`.
+pub fn headers(secret: &[u8], timestamp: i64, nonce: &str, body: &str) -> [(String, String); 3] {
+    [
+        (
+            SIGNATURE_HEADER.to_string(),
+            format!("sha256={}", sign(secret, timestamp, nonce, body)),
+        ),
+        (TIMESTAMP_HEADER.to_string(), timestamp.to_string()),
+        (NONCE_HEADER.to_string(), nonce.to_string()),
+    ]
+}
+
+/// Lowercase hex of `bytes`.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"s3cret";
+    const TIMESTAMP: i64 = 1_700_000_000;
+    const NONCE: &str = "00112233445566778899aabbccddeeff";
+    const BODY: &str = r#"{"measurements":[]}"#;
+
+    /// From `printf '1700000000\n00112233445566778899aabbccddeeff\n{"measurements":[]}'
+    /// | openssl dgst -sha256 -hmac s3cret`.
+    const SIGNATURE: &str = "7f9b5b4d7b07a20d04fe7fdc9e0396e7c5381088b16026718b6a2f49dd2482dc";
+
+    #[test]
+    fn canonical_joins_with_newlines() {
+        assert_eq!(
+            canonical(TIMESTAMP, NONCE, BODY),
+            "1700000000\n00112233445566778899aabbccddeeff\n{\"measurements\":[]}"
+        );
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(sign(SECRET, TIMESTAMP, NONCE, BODY), SIGNATURE);
+        // The widely published HMAC-SHA256 example, to check the hex
+        // encoding independently of the canonical string.
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"key").unwrap();
+        mac.update(b"The quick brown fox jumps over the lazy dog");
+        assert_eq!(
+            hex(&mac.finalize().into_bytes()),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn signature_covers_every_part() {
+        assert_ne!(sign(b"other", TIMESTAMP, NONCE, BODY), SIGNATURE);
+        assert_ne!(sign(SECRET, TIMESTAMP + 1, NONCE, BODY), SIGNATURE);
+        assert_ne!(sign(SECRET, TIMESTAMP, "00", BODY), SIGNATURE);
+        assert_ne!(sign(SECRET, TIMESTAMP, NONCE, "{}"), SIGNATURE);
+    }
+
+    #[test]
+    fn headers_carry_signature_timestamp_and_nonce() {
+        let [signature, timestamp, nonce] = headers(SECRET, TIMESTAMP, NONCE, BODY);
+        assert_eq!(
+            signature,
+            ("x-signature".to_string(), format!("sha256={}", SIGNATURE))
+        );
+        assert_eq!(
+            timestamp,
+            (
+                "x-signature-timestamp".to_string(),
+                "1700000000".to_string()
+            )
+        );
+        assert_eq!(nonce, ("x-signature-nonce".to_string(), NONCE.to_string()));
+    }
+
+    #[test]
+    fn nonce_is_hex_of_random_bytes() {
+        assert_eq!(nonce(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+    }
+}
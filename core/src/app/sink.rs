@@ -0,0 +1,173 @@
+use anyhow::{anyhow, bail, Error, Result};
+use log::*;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::app::effects::Sink;
+use crate::app::state::State;
+
+/// The outputs measurements can be sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    /// The configured server, in the configured payload format. Failed
+    /// uploads are queued and retried.
+    Http,
+    Mqtt,
+    Log,
+}
+
+impl SinkKind {
+    pub const ALL: [SinkKind; 3] = [SinkKind::Http, SinkKind::Mqtt, SinkKind::Log];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkKind::Http => "http",
+            SinkKind::Mqtt => "mqtt",
+            SinkKind::Log => "log",
+        }
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = Error;
+
+    fn from_str(kind: &str) -> Result<Self> {
+        SinkKind::ALL
+            .into_iter()
+            .find(|sink| sink.name() == kind)
+            .ok_or_else(|| anyhow!("Unknown sink {:?}", kind))
+    }
+}
+
+impl fmt::Display for SinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The sink whose first successful send marks a new firmware valid: the
+/// upload if configured, otherwise the first sink that leaves the device.
+/// With only the log there is nothing to confirm, so any send will do.
+pub fn confirming_sink(sinks: &[SinkKind]) -> Option<SinkKind> {
+    if sinks.contains(&SinkKind::Http) {
+        return Some(SinkKind::Http);
+    }
+    sinks.iter().copied().find(|&kind| kind != SinkKind::Log)
+}
+
+/// Parse the comma separated list of sinks to send measurements to.
+pub fn parse_sinks(sinks: &str) -> Result<Vec<SinkKind>> {
+    let mut kinds = Vec::new();
+    for name in sinks
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let kind = name.parse()?;
+        if kinds.contains(&kind) {
+            bail!("sinks lists {} more than once", kind);
+        }
+        kinds.push(kind);
+    }
+    if kinds.is_empty() {
+        bail!("sinks must list at least one of http, mqtt or log");
+    }
+    Ok(kinds)
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SinkCounters {
+    pub sent: u64,
+    pub failed: u64,
+}
+
+/// Sends to each sink that succeeded and failed.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SinkStats {
+    pub http: SinkCounters,
+    pub mqtt: SinkCounters,
+    pub log: SinkCounters,
+}
+
+impl SinkStats {
+    pub fn get(&self, kind: SinkKind) -> SinkCounters {
+        match kind {
+            SinkKind::Http => self.http,
+            SinkKind::Mqtt => self.mqtt,
+            SinkKind::Log => self.log,
+        }
+    }
+
+    pub fn record(mut self, kind: SinkKind, sent: bool) -> Self {
+        let counters = match kind {
+            SinkKind::Http => &mut self.http,
+            SinkKind::Mqtt => &mut self.mqtt,
+            SinkKind::Log => &mut self.log,
+        };
+        if sent {
+            counters.sent += 1;
+        } else {
+            counters.failed += 1;
+        }
+        self
+    }
+}
+
+/// Writes each measurement to the log, e.g. to follow the readings on the
+/// serial console.
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Log
+    }
+
+    fn send(&mut self, state: &State) -> Result<()> {
+        for measurement in state.measurements.iter() {
+            let metadata = &measurement.metadata;
+            let fields: Vec<String> = measurement
+                .fields()
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            info!(
+                "{} {} at {}: {}",
+                metadata.model.name(),
+                metadata.serial_number,
+                metadata.timestamp,
+                fields.join(" ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_confirms_firmware_when_configured() {
+        assert_eq!(
+            confirming_sink(&[SinkKind::Log, SinkKind::Mqtt, SinkKind::Http]),
+            Some(SinkKind::Http)
+        );
+        assert_eq!(
+            confirming_sink(&[SinkKind::Log, SinkKind::Mqtt]),
+            Some(SinkKind::Mqtt)
+        );
+        assert_eq!(confirming_sink(&[SinkKind::Log]), None);
+    }
+
+    #[test]
+    fn parses_sinks() {
+        assert_eq!(
+            parse_sinks("mqtt, http").unwrap(),
+            [SinkKind::Mqtt, SinkKind::Http]
+        );
+        assert!(parse_sinks("http,http").is_err());
+        assert!(parse_sinks(" , ").is_err());
+        assert!(parse_sinks("ftp").is_err());
+    }
+}
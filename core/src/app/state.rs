@@ -0,0 +1,1129 @@
+use serde::ser::{SerializeMap, SerializeStruct, Serializer};
+use serde::Serialize;
+use time::{Duration, OffsetDateTime};
+
+use crate::app::radon::RadonSchedule;
+use crate::app::sink::{SinkKind, SinkStats};
+use crate::waveplus::address::Address;
+use crate::waveplus::measurement::Measurement;
+
+/// Delay before rescanning after the first unsuccessful scan.
+const SCAN_BACKOFF_MS: u32 = 5_000;
+const MAX_SCAN_BACKOFF_MS: u32 = 600_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+    Initialize,
+    Reinitialize,
+    ScanBackoff,
+    CollectMeasurement,
+    SendMeasurement,
+    SendQueued,
+    Wait,
+    WifiDisconnect,
+    WifiReconnect,
+}
+
+/// Outcome of performing an [`Action`], fed back into [`State::step`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    WavePlusFound(Vec<(u32, Address)>),
+    ScanFailed,
+    BackoffElapsed,
+    AddressesSaved,
+    AddressesForgotten,
+    /// The readings of the devices that were read, the devices that could
+    /// not be read and those whose readings came in an unsupported version.
+    MeasurementsRead {
+        measurements: Vec<Measurement>,
+        failed: Vec<u32>,
+        unsupported: Vec<u32>,
+    },
+    /// The measurements were offered to every sink. `link_lost` is set when
+    /// the upload to the server failed because the Wi-Fi link is down, a
+    /// failure with the link up means reconnecting won't help. `rejected`
+    /// is set when the server refused the upload, which resending won't
+    /// fix either.
+    MeasurementSent {
+        sent: Vec<SinkKind>,
+        failed: Vec<SinkKind>,
+        link_lost: bool,
+        rejected: bool,
+    },
+    MeasurementsQueued(usize),
+    RadonReportSaved,
+    QueuedSent(usize),
+    /// The server refused a batch of queued measurements, which was
+    /// dropped, leaving this many queued.
+    QueuedDropped(usize),
+    /// Sending a batch of queued measurements failed because the Wi-Fi link
+    /// is down, so reconnecting could help.
+    QueuedSendFailedLinkDown,
+    /// Sending a batch of queued measurements failed with the link up, such
+    /// as a server error or retries running out, which reconnecting won't
+    /// help.
+    QueuedSendFailedLinkUp,
+    /// The queue could not be read or updated in storage, leaving this many
+    /// queued for the next cycle.
+    QueueFailed(usize),
+    WifiDisconnected,
+    WifiConnected,
+    /// Waiting for the Wi-Fi link to come back failed.
+    WifiReconnectFailed,
+    WaitElapsed,
+}
+
+/// Side effect requested by [`State::step`], performed by the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    ScanWavePlus,
+    SaveAddresses(Vec<(u32, Address)>),
+    /// Drop the cached addresses of devices that could not be read, so
+    /// that they are scanned for again after a reboot too.
+    ForgetAddresses(Vec<u32>),
+    ReadWavePlus {
+        serials: Vec<u32>,
+        radon: Vec<u32>,
+    },
+    SendMeasurement,
+    EnqueueMeasurements(Vec<Measurement>),
+    SendQueued,
+    /// Persist that the given devices reported radon at a time.
+    SaveRadonReport(Vec<u32>, OffsetDateTime),
+    DisconnectWifi,
+    WaitForWifi,
+    Backoff(u32),
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Status {
+    Initializing,
+    Ready,
+    Collecting,
+    Sending,
+    Error,
+    Recovering,
+}
+
+impl From<ExecutionMode> for Status {
+    fn from(mode: ExecutionMode) -> Status {
+        match mode {
+            ExecutionMode::Initialize => Status::Initializing,
+            ExecutionMode::Reinitialize => Status::Recovering,
+            ExecutionMode::ScanBackoff => Status::Error,
+            ExecutionMode::CollectMeasurement => Status::Collecting,
+            ExecutionMode::SendMeasurement => Status::Sending,
+            ExecutionMode::SendQueued => Status::Sending,
+            ExecutionMode::Wait => Status::Ready,
+            ExecutionMode::WifiDisconnect => Status::Error,
+            ExecutionMode::WifiReconnect => Status::Recovering,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Errors {
+    pub wifi_disconnects: u64,
+    pub ble_disconnects: u64,
+    pub ble_scan_failures: u64,
+    pub http_errors: u64,
+    pub sinks: SinkStats,
+}
+
+impl Errors {
+    fn wifi_disconnected(&self) -> Self {
+        Errors {
+            wifi_disconnects: self.wifi_disconnects + 1,
+            ..*self
+        }
+    }
+
+    fn ble_disconnected(&self) -> Self {
+        Errors {
+            ble_disconnects: self.ble_disconnects + 1,
+            ..*self
+        }
+    }
+
+    fn ble_scan_failed(&self) -> Self {
+        Errors {
+            ble_scan_failures: self.ble_scan_failures + 1,
+            ..*self
+        }
+    }
+
+    fn http_error(&self) -> Self {
+        Errors {
+            http_errors: self.http_errors + 1,
+            ..*self
+        }
+    }
+
+    fn sink_results(&self, sent: &[SinkKind], failed: &[SinkKind]) -> Self {
+        let sinks = sent
+            .iter()
+            .map(|kind| (kind, true))
+            .chain(failed.iter().map(|kind| (kind, false)))
+            .fold(self.sinks, |sinks, (kind, ok)| sinks.record(*kind, ok));
+        Errors { sinks, ..*self }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeviceErrors {
+    pub ble_disconnects: u64,
+    pub not_found: u64,
+    /// Readings in a payload version this firmware can't decode.
+    pub unsupported_payloads: u64,
+}
+
+/// A configured Wave Plus and, once resolved by a scan or loaded from the
+/// cache, its BLE address.
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub serial: u32,
+    pub address: Option<Address>,
+    /// When it was last read, to space out reads by the minimum read
+    /// interval.
+    pub last_read: Option<OffsetDateTime>,
+    /// When its radon was last reported.
+    pub last_radon: Option<OffsetDateTime>,
+    pub errors: DeviceErrors,
+}
+
+impl Device {
+    fn new(serial: u32) -> Self {
+        Device {
+            serial,
+            address: None,
+            last_read: None,
+            last_radon: None,
+            errors: DeviceErrors::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+    pub mode: ExecutionMode,
+    pub status: Status,
+    pub radon: RadonSchedule,
+    /// The minimum time between two reads of a device, longer than the
+    /// read interval to save its battery.
+    pub min_read_interval: Option<Duration>,
+    pub measurements: Vec<Measurement>,
+    pub devices: Vec<Device>,
+    pub errors: Errors,
+    /// Consecutive scans that failed or resolved no device, setting the
+    /// backoff before the next one.
+    pub scan_attempts: u32,
+    pub queued: usize,
+    /// The running firmware version, reported with the error counters.
+    pub firmware: &'static str,
+}
+
+struct DevicesErrors<'a>(&'a [Device]);
+
+impl Serialize for DevicesErrors<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for device in self.0 {
+            map.serialize_entry(&device.serial.to_string(), &device.errors)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for State {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("State", 4)?;
+
+        state.serialize_field("measurements", &self.measurements)?;
+        state.serialize_field("errors", &self.errors)?;
+        state.serialize_field("device_errors", &DevicesErrors(&self.devices))?;
+        state.serialize_field("firmware", self.firmware)?;
+
+        state.end()
+    }
+}
+
+impl State {
+    pub fn new(serials: &[u32], radon: RadonSchedule) -> Self {
+        State {
+            mode: ExecutionMode::Initialize,
+            status: Status::Ready,
+            radon,
+            min_read_interval: None,
+            measurements: Vec::new(),
+            devices: serials.iter().copied().map(Device::new).collect(),
+            errors: Errors::default(),
+            scan_attempts: 0,
+            queued: 0,
+            firmware: "",
+        }
+    }
+
+    /// The actions to perform before any event has been received,
+    /// connecting straight to the cached addresses when all are known.
+    pub fn start(self, now: OffsetDateTime) -> (State, Vec<Action>) {
+        if self.devices.iter().any(|device| device.address.is_none()) {
+            (self, vec![Action::ScanWavePlus])
+        } else {
+            self.with_mode(ExecutionMode::CollectMeasurement).read(now)
+        }
+    }
+
+    /// Apply `event` to the current state, returning the new state and
+    /// the actions that should be performed next.  This performs no I/O so
+    /// that the recovery logic can be exercised off-device.
+    pub fn step(self, event: Event, now: OffsetDateTime) -> (State, Vec<Action>) {
+        match event {
+            Event::WavePlusFound(found) => {
+                let newstate = self
+                    .with_mode(ExecutionMode::CollectMeasurement)
+                    .with_addresses(&found);
+                if newstate
+                    .devices
+                    .iter()
+                    .all(|device| device.address.is_none())
+                {
+                    return newstate.scan_backoff();
+                }
+                let (newstate, next) = State {
+                    scan_attempts: 0,
+                    ..newstate
+                }
+                .read(now);
+                if found.is_empty() {
+                    (newstate, next)
+                } else {
+                    let mut actions = vec![Action::SaveAddresses(found)];
+                    actions.extend(next);
+                    (newstate, actions)
+                }
+            }
+            Event::ScanFailed => self.ble_scan_failed().scan_backoff(),
+            Event::BackoffElapsed => self.collect(now),
+            Event::AddressesSaved | Event::AddressesForgotten => (self, vec![]),
+            Event::MeasurementsRead {
+                measurements,
+                failed,
+                unsupported,
+            } => {
+                let read: Vec<u32> = measurements
+                    .iter()
+                    .map(|measurement| measurement.metadata.serial_number)
+                    .collect();
+                // A device that sent an unsupported payload was reached, so
+                // it keeps its address.
+                let newstate = self
+                    .with_read_failures(&failed)
+                    .with_unsupported_payloads(&unsupported)
+                    .with_reads(&read, now);
+                let mut actions = Vec::new();
+                if !failed.is_empty() {
+                    actions.push(Action::ForgetAddresses(failed));
+                }
+                if measurements.is_empty() && actions.is_empty() {
+                    // Every device read sent an unsupported payload.
+                    newstate.drain()
+                } else if measurements.is_empty() {
+                    actions.push(Action::ScanWavePlus);
+                    (newstate.with_mode(ExecutionMode::Reinitialize), actions)
+                } else {
+                    newstate.send(ExecutionMode::SendMeasurement, measurements, actions)
+                }
+            }
+            Event::MeasurementSent {
+                sent,
+                failed,
+                link_lost,
+                rejected,
+            } => {
+                // Only the upload is queued and can call for reconnecting,
+                // the other sinks just count their failures.
+                let newstate = self.sink_results(&sent, &failed);
+                if failed.contains(&SinkKind::Http) {
+                    if link_lost {
+                        newstate.send_failed(
+                            now,
+                            ExecutionMode::WifiDisconnect,
+                            Action::DisconnectWifi,
+                        )
+                    } else if rejected {
+                        // Queued, it would only block the measurements
+                        // behind it.
+                        (
+                            newstate.with_mode(ExecutionMode::Wait).http_error(),
+                            vec![Action::Wait],
+                        )
+                    } else {
+                        newstate.send_failed(now, ExecutionMode::Wait, Action::Wait)
+                    }
+                } else {
+                    let (newstate, mut actions) = newstate.radon_reported(now);
+                    let (newstate, next) = newstate.drain();
+                    actions.extend(next);
+                    (newstate, actions)
+                }
+            }
+            Event::RadonReportSaved => (self, vec![]),
+            Event::MeasurementsQueued(queued) => (self.with_queued(queued), vec![]),
+            Event::QueuedSent(queued) => self.with_queued(queued).drain(),
+            Event::QueuedDropped(queued) => self.with_queued(queued).http_error().drain(),
+            Event::QueuedSendFailedLinkUp => (
+                self.with_mode(ExecutionMode::Wait).http_error(),
+                vec![Action::Wait],
+            ),
+            Event::QueuedSendFailedLinkDown => (
+                self.with_mode(ExecutionMode::WifiDisconnect).http_error(),
+                vec![Action::DisconnectWifi],
+            ),
+            Event::QueueFailed(queued) => (
+                self.with_queued(queued).with_mode(ExecutionMode::Wait),
+                vec![Action::Wait],
+            ),
+            Event::WifiReconnectFailed => (self.with_mode(ExecutionMode::Wait), vec![Action::Wait]),
+            Event::WifiDisconnected => (
+                self.with_mode(ExecutionMode::WifiReconnect)
+                    .wifi_disconnected(),
+                vec![Action::WaitForWifi],
+            ),
+            Event::WifiConnected | Event::WaitElapsed => self
+                .with_mode(ExecutionMode::CollectMeasurement)
+                .collect(now),
+        }
+    }
+
+    /// Send `measurements` in `mode` after `actions`. While older
+    /// measurements are queued they are uploaded from the back of the queue
+    /// instead, so that the server receives them in order.
+    fn send(
+        self,
+        mode: ExecutionMode,
+        measurements: Vec<Measurement>,
+        mut actions: Vec<Action>,
+    ) -> (State, Vec<Action>) {
+        if self.queued > 0 {
+            actions.push(Action::EnqueueMeasurements(measurements.clone()));
+        }
+        actions.push(Action::SendMeasurement);
+        (
+            self.with_mode(mode).with_measurements(measurements),
+            actions,
+        )
+    }
+
+    /// Queue the measurements that failed to upload and continue with
+    /// `next` in `mode`.
+    fn send_failed(
+        self,
+        now: OffsetDateTime,
+        mode: ExecutionMode,
+        next: Action,
+    ) -> (State, Vec<Action>) {
+        // The measurement is queued with its radon values, so they count
+        // as reported.
+        let measurements = self.measurements.clone();
+        let (newstate, mut actions) = self.radon_reported(now);
+        actions.extend([Action::EnqueueMeasurements(measurements), next]);
+        (newstate.with_mode(mode).http_error(), actions)
+    }
+
+    /// Upload any queued measurements before waiting for the next cycle.
+    fn drain(self) -> (State, Vec<Action>) {
+        if self.queued > 0 {
+            (
+                self.with_mode(ExecutionMode::SendQueued),
+                vec![Action::SendQueued],
+            )
+        } else {
+            (self.with_mode(ExecutionMode::Wait), vec![Action::Wait])
+        }
+    }
+
+    /// Wait before the next scan, doubling the delay with each consecutive
+    /// unsuccessful scan up to [`MAX_SCAN_BACKOFF_MS`].
+    fn scan_backoff(self) -> (State, Vec<Action>) {
+        let delay = SCAN_BACKOFF_MS
+            .saturating_mul(1 << self.scan_attempts.min(16))
+            .min(MAX_SCAN_BACKOFF_MS);
+        (
+            State {
+                scan_attempts: self.scan_attempts + 1,
+                ..self.with_mode(ExecutionMode::ScanBackoff)
+            },
+            vec![Action::Backoff(delay)],
+        )
+    }
+
+    /// Start a collection cycle, first rescanning if any configured Wave
+    /// Plus has not been resolved.
+    fn collect(self, now: OffsetDateTime) -> (State, Vec<Action>) {
+        if self.devices.iter().any(|device| device.address.is_none()) {
+            return (
+                self.with_mode(ExecutionMode::Reinitialize),
+                vec![Action::ScanWavePlus],
+            );
+        }
+        self.read(now)
+    }
+
+    /// Read from every resolved Wave Plus, without rescanning for missing
+    /// ones.
+    fn read(self, now: OffsetDateTime) -> (State, Vec<Action>) {
+        if self.devices.iter().all(|device| device.address.is_none()) {
+            return (
+                self.with_mode(ExecutionMode::Reinitialize),
+                vec![Action::ScanWavePlus],
+            );
+        }
+        let radon = self.radon_due(now);
+        let serials: Vec<u32> = self
+            .devices
+            .iter()
+            .filter(|device| device.address.is_some())
+            .filter(|device| self.read_due(device, now))
+            .map(|device| device.serial)
+            .collect();
+        if serials.is_empty() {
+            // No device is due for a read yet.
+            return self.drain();
+        }
+        (self, vec![Action::ReadWavePlus { serials, radon }])
+    }
+
+    /// The devices whose radon is due to be reported at `now`.
+    fn radon_due(&self, now: OffsetDateTime) -> Vec<u32> {
+        self.devices
+            .iter()
+            .filter(|device| self.radon.is_due(device.last_radon, now))
+            .map(|device| device.serial)
+            .collect()
+    }
+
+    /// Whether `device` is due to be read, always without a minimum read
+    /// interval.
+    fn read_due(&self, device: &Device, now: OffsetDateTime) -> bool {
+        match (self.min_read_interval, device.last_read) {
+            (Some(interval), Some(last)) => now - last >= interval,
+            _ => true,
+        }
+    }
+
+    pub fn wifi_disconnected(self) -> Self {
+        State {
+            errors: self.errors.wifi_disconnected(),
+            ..self
+        }
+    }
+
+    pub fn ble_scan_failed(self) -> Self {
+        State {
+            errors: self.errors.ble_scan_failed(),
+            ..self
+        }
+    }
+
+    pub fn http_error(self) -> Self {
+        State {
+            errors: self.errors.http_error(),
+            ..self
+        }
+    }
+
+    pub fn sink_results(self, sent: &[SinkKind], failed: &[SinkKind]) -> Self {
+        State {
+            errors: self.errors.sink_results(sent, failed),
+            ..self
+        }
+    }
+
+    pub fn with_mode(self, mode: ExecutionMode) -> Self {
+        State {
+            mode,
+            status: Status::from(mode),
+            measurements: Vec::new(),
+            ..self
+        }
+    }
+
+    pub fn with_queued(self, queued: usize) -> Self {
+        State { queued, ..self }
+    }
+
+    /// Payload for measurements replayed from the queue, reported with the
+    /// current error counters.
+    pub fn queued_payload(&self, measurements: &[Measurement]) -> serde_json::Value {
+        serde_json::json!({
+            "measurements": measurements,
+            "errors": self.errors,
+            "device_errors": DevicesErrors(&self.devices),
+            "firmware": self.firmware,
+        })
+    }
+
+    /// Restore when each device last reported radon, as persisted.
+    pub fn with_radon_reports(mut self, reports: &[(u32, OffsetDateTime)]) -> Self {
+        for device in self.devices.iter_mut() {
+            if let Some((_, at)) = reports.iter().find(|(serial, _)| *serial == device.serial) {
+                device.last_radon = Some(*at);
+            }
+        }
+        self
+    }
+
+    pub fn with_firmware(self, firmware: &'static str) -> Self {
+        State { firmware, ..self }
+    }
+
+    pub fn with_min_read_interval(self, min_read_interval: Option<Duration>) -> Self {
+        State {
+            min_read_interval,
+            ..self
+        }
+    }
+
+    /// Record when the devices in `serials` were read.
+    pub fn with_reads(mut self, serials: &[u32], now: OffsetDateTime) -> Self {
+        for device in self.devices.iter_mut() {
+            if serials.contains(&device.serial) {
+                device.last_read = Some(now);
+            }
+        }
+        self
+    }
+
+    /// Record that the current measurements have been reported, persisting
+    /// the time for the devices whose measurement included radon.
+    fn radon_reported(mut self, now: OffsetDateTime) -> (State, Vec<Action>) {
+        let serials: Vec<u32> = self
+            .measurements
+            .iter()
+            .filter(|measurement| measurement.has_radon())
+            .map(|measurement| measurement.metadata.serial_number)
+            .collect();
+        if serials.is_empty() {
+            return (self, vec![]);
+        }
+        for device in self.devices.iter_mut() {
+            if serials.contains(&device.serial) {
+                device.last_radon = Some(now);
+            }
+        }
+        (self, vec![Action::SaveRadonReport(serials, now)])
+    }
+
+    pub fn with_measurements(self, measurements: Vec<Measurement>) -> Self {
+        State {
+            measurements,
+            ..self
+        }
+    }
+
+    /// Record the devices resolved by a scan, counting the unresolved ones
+    /// that were not seen.
+    pub fn with_addresses(mut self, found: &[(u32, Address)]) -> Self {
+        for device in self.devices.iter_mut() {
+            match found.iter().find(|(serial, _)| *serial == device.serial) {
+                Some((_, address)) => device.address = Some(*address),
+                None if device.address.is_none() => device.errors.not_found += 1,
+                None => {}
+            }
+        }
+        self
+    }
+
+    /// Use the addresses cached from earlier scans until a read from them
+    /// fails.
+    pub fn with_cached_addresses(mut self, cached: &[(u32, Address)]) -> Self {
+        for device in self.devices.iter_mut() {
+            if let Some((_, address)) = cached.iter().find(|(serial, _)| *serial == device.serial) {
+                device.address = Some(*address);
+            }
+        }
+        self
+    }
+
+    pub fn with_unsupported_payloads(mut self, unsupported: &[u32]) -> Self {
+        for device in self.devices.iter_mut() {
+            if unsupported.contains(&device.serial) {
+                device.errors.unsupported_payloads += 1;
+            }
+        }
+        self
+    }
+
+    /// Forget the address of every Wave Plus that failed to read so that it
+    /// is rescanned on the next cycle.
+    pub fn with_read_failures(mut self, failed: &[u32]) -> Self {
+        for device in self.devices.iter_mut() {
+            if failed.contains(&device.serial) {
+                device.address = None;
+                device.errors.ble_disconnects += 1;
+                self.errors = self.errors.ble_disconnected();
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waveplus::model::Model;
+
+    const SERIAL: u32 = 2930_123456;
+    const ADDRESS: Address = Address {
+        bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+        kind: 0,
+    };
+
+    fn at(seconds: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(1_700_000_000 + seconds).unwrap()
+    }
+
+    fn measurement() -> Measurement {
+        // 45 %, radon 100 and 110 Bq/m³, 21.5 °C, 1000 hPa, 600 ppm CO2,
+        // 150 ppb VOC.
+        let packet = [
+            1, 90, 0, 0, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        let data = Model::WavePlus.decode(&packet).unwrap();
+        Measurement::logged(SERIAL, Model::WavePlus, ADDRESS, data, at(0)).unwrap()
+    }
+
+    /// A state that has just read a measurement and is about to send it.
+    fn read() -> State {
+        let (state, actions) = State::new(&[SERIAL], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .start(at(0));
+        assert_eq!(
+            actions,
+            vec![Action::ReadWavePlus {
+                serials: vec![SERIAL],
+                radon: vec![SERIAL],
+            }]
+        );
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![measurement()],
+                failed: vec![],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(state.mode, ExecutionMode::SendMeasurement);
+        assert_eq!(actions, vec![Action::SendMeasurement]);
+        state
+    }
+
+    #[test]
+    fn scan_failures_back_off_exponentially() {
+        let (state, actions) = State::new(&[SERIAL], RadonSchedule::default()).start(at(0));
+        assert_eq!(actions, vec![Action::ScanWavePlus]);
+
+        let (state, actions) = state.step(Event::ScanFailed, at(1));
+        assert_eq!(state.mode, ExecutionMode::ScanBackoff);
+        assert_eq!(state.errors.ble_scan_failures, 1);
+        assert_eq!(actions, vec![Action::Backoff(SCAN_BACKOFF_MS)]);
+
+        let (state, actions) = state.step(Event::BackoffElapsed, at(6));
+        assert_eq!(state.mode, ExecutionMode::Reinitialize);
+        assert_eq!(actions, vec![Action::ScanWavePlus]);
+
+        let (state, actions) = state.step(Event::ScanFailed, at(7));
+        assert_eq!(state.errors.ble_scan_failures, 2);
+        assert_eq!(actions, vec![Action::Backoff(2 * SCAN_BACKOFF_MS)]);
+
+        let (state, actions) = state.step(Event::WavePlusFound(vec![(SERIAL, ADDRESS)]), at(20));
+        assert_eq!(state.mode, ExecutionMode::CollectMeasurement);
+        assert_eq!(state.scan_attempts, 0);
+        assert_eq!(
+            actions,
+            vec![
+                Action::SaveAddresses(vec![(SERIAL, ADDRESS)]),
+                Action::ReadWavePlus {
+                    serials: vec![SERIAL],
+                    radon: vec![SERIAL],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn min_read_interval_reads_each_device_once_per_interval() {
+        let (state, _) = State::new(&[SERIAL], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .with_min_read_interval(Some(Duration::minutes(60)))
+            .start(at(0));
+        let (state, _) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![measurement()],
+                failed: vec![],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(state.devices[0].last_read, Some(at(1)));
+
+        let (state, actions) = state.step(Event::WaitElapsed, at(600));
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(actions, vec![Action::Wait]);
+
+        let (_, actions) = state.step(Event::WaitElapsed, at(3601));
+        assert_eq!(
+            actions,
+            vec![Action::ReadWavePlus {
+                serials: vec![SERIAL],
+                radon: vec![SERIAL],
+            }]
+        );
+    }
+
+    #[test]
+    fn read_failure_rescans_for_the_device() {
+        let (state, _) = State::new(&[SERIAL], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .start(at(0));
+
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![],
+                failed: vec![SERIAL],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(state.mode, ExecutionMode::Reinitialize);
+        assert_eq!(state.devices[0].address, None);
+        assert_eq!(state.devices[0].errors.ble_disconnects, 1);
+        assert_eq!(state.errors.ble_disconnects, 1);
+        assert_eq!(
+            actions,
+            vec![Action::ForgetAddresses(vec![SERIAL]), Action::ScanWavePlus]
+        );
+
+        let (state, actions) = state.step(Event::WavePlusFound(vec![(SERIAL, ADDRESS)]), at(2));
+        assert_eq!(state.mode, ExecutionMode::CollectMeasurement);
+        assert_eq!(state.devices[0].address, Some(ADDRESS));
+        assert_eq!(state.errors.ble_disconnects, 1);
+        assert_eq!(actions.len(), 2);
+    }
+
+    #[test]
+    fn partial_read_failure_forgets_the_address() {
+        const OTHER: u32 = 2930_654321;
+        let (state, _) = State::new(&[SERIAL, OTHER], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS), (OTHER, ADDRESS)])
+            .start(at(0));
+
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![measurement()],
+                failed: vec![OTHER],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(state.mode, ExecutionMode::SendMeasurement);
+        assert_eq!(state.devices[1].address, None);
+        assert_eq!(
+            actions,
+            vec![
+                Action::ForgetAddresses(vec![OTHER]),
+                Action::SendMeasurement,
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_upload_with_link_down_reconnects_wifi() {
+        let (state, actions) = read().step(
+            Event::MeasurementSent {
+                sent: vec![SinkKind::Mqtt],
+                failed: vec![SinkKind::Http],
+                link_lost: true,
+                rejected: false,
+            },
+            at(2),
+        );
+        assert_eq!(state.mode, ExecutionMode::WifiDisconnect);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(state.errors.sinks.http.failed, 1);
+        assert_eq!(state.errors.sinks.mqtt.sent, 1);
+        assert_eq!(
+            actions,
+            vec![
+                Action::SaveRadonReport(vec![SERIAL], at(2)),
+                Action::EnqueueMeasurements(vec![measurement()]),
+                Action::DisconnectWifi,
+            ]
+        );
+
+        let (state, actions) = state.step(Event::WifiDisconnected, at(3));
+        assert_eq!(state.mode, ExecutionMode::WifiReconnect);
+        assert_eq!(state.errors.wifi_disconnects, 1);
+        assert_eq!(actions, vec![Action::WaitForWifi]);
+
+        // Radon was queued with the measurement, so it isn't due again in
+        // the same hour.
+        let (state, actions) = state.step(Event::WifiConnected, at(4));
+        assert_eq!(state.mode, ExecutionMode::CollectMeasurement);
+        assert_eq!(state.errors.wifi_disconnects, 1);
+        assert_eq!(
+            actions,
+            vec![Action::ReadWavePlus {
+                serials: vec![SERIAL],
+                radon: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn failed_upload_with_link_up_waits() {
+        let (state, actions) = read().step(
+            Event::MeasurementSent {
+                sent: vec![],
+                failed: vec![SinkKind::Http],
+                link_lost: false,
+                rejected: false,
+            },
+            at(2),
+        );
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(state.errors.wifi_disconnects, 0);
+        assert_eq!(actions.last(), Some(&Action::Wait));
+    }
+
+    #[test]
+    fn rejected_upload_is_not_queued() {
+        let (state, actions) = read().step(
+            Event::MeasurementSent {
+                sent: vec![],
+                failed: vec![SinkKind::Http],
+                link_lost: false,
+                rejected: true,
+            },
+            at(2),
+        );
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(actions, vec![Action::Wait]);
+    }
+
+    #[test]
+    fn queue_failure_waits_for_the_next_cycle() {
+        let (state, actions) = read()
+            .with_queued(3)
+            .with_mode(ExecutionMode::SendQueued)
+            .step(Event::QueueFailed(3), at(2));
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.queued, 3);
+        assert_eq!(actions, vec![Action::Wait]);
+    }
+
+    #[test]
+    fn failed_reconnect_waits_for_the_next_cycle() {
+        let (state, actions) = read()
+            .with_mode(ExecutionMode::WifiReconnect)
+            .step(Event::WifiReconnectFailed, at(2));
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(actions, vec![Action::Wait]);
+
+        let (state, actions) = state.step(Event::WaitElapsed, at(300));
+        assert_eq!(state.mode, ExecutionMode::CollectMeasurement);
+        assert!(matches!(actions[..], [Action::ReadWavePlus { .. }]));
+    }
+
+    #[test]
+    fn queued_send_failure_with_link_down_reconnects_wifi() {
+        let (state, actions) = read()
+            .with_queued(3)
+            .with_mode(ExecutionMode::SendQueued)
+            .step(Event::QueuedSendFailedLinkDown, at(2));
+        assert_eq!(state.mode, ExecutionMode::WifiDisconnect);
+        assert_eq!(state.queued, 3);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(actions, vec![Action::DisconnectWifi]);
+    }
+
+    #[test]
+    fn queued_send_failure_with_link_up_waits() {
+        let (state, actions) = read()
+            .with_queued(3)
+            .with_mode(ExecutionMode::SendQueued)
+            .step(Event::QueuedSendFailedLinkUp, at(2));
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.queued, 3);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(actions, vec![Action::Wait]);
+    }
+
+    #[test]
+    fn rejected_queued_batch_is_dropped() {
+        let (state, actions) = read()
+            .with_queued(3)
+            .with_mode(ExecutionMode::SendQueued)
+            .step(Event::QueuedDropped(1), at(2));
+        assert_eq!(state.mode, ExecutionMode::SendQueued);
+        assert_eq!(state.queued, 1);
+        assert_eq!(state.errors.http_errors, 1);
+        assert_eq!(actions, vec![Action::SendQueued]);
+    }
+
+    #[test]
+    fn failed_secondary_sink_is_only_counted() {
+        let (state, actions) = read().step(
+            Event::MeasurementSent {
+                sent: vec![SinkKind::Http],
+                failed: vec![SinkKind::Mqtt],
+                link_lost: false,
+                rejected: false,
+            },
+            at(2),
+        );
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.errors.http_errors, 0);
+        assert_eq!(state.errors.sinks.mqtt.failed, 1);
+        assert_eq!(
+            actions,
+            vec![Action::SaveRadonReport(vec![SERIAL], at(2)), Action::Wait]
+        );
+    }
+
+    #[test]
+    fn reading_behind_a_backlog_is_queued() {
+        let (state, _) = State::new(&[SERIAL], RadonSchedule::default())
+            .with_queued(2)
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .start(at(0));
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![measurement()],
+                failed: vec![],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(state.mode, ExecutionMode::SendMeasurement);
+        assert_eq!(
+            actions,
+            vec![
+                Action::EnqueueMeasurements(vec![measurement()]),
+                Action::SendMeasurement,
+            ]
+        );
+
+        let state = state.step(Event::MeasurementsQueued(3), at(1)).0;
+        let (state, actions) = state.step(
+            Event::MeasurementSent {
+                sent: vec![SinkKind::Mqtt],
+                failed: vec![],
+                link_lost: false,
+                rejected: false,
+            },
+            at(2),
+        );
+        assert_eq!(state.mode, ExecutionMode::SendQueued);
+        assert_eq!(state.queued, 3);
+        assert_eq!(
+            actions,
+            vec![
+                Action::SaveRadonReport(vec![SERIAL], at(2)),
+                Action::SendQueued
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_payload_keeps_the_device() {
+        let (state, actions) = State::new(&[SERIAL], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .start(at(0));
+        assert!(matches!(actions[..], [Action::ReadWavePlus { .. }]));
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![],
+                failed: vec![],
+                unsupported: vec![SERIAL],
+            },
+            at(1),
+        );
+        assert_eq!(state.mode, ExecutionMode::Wait);
+        assert_eq!(state.devices[0].address, Some(ADDRESS));
+        assert_eq!(state.devices[0].errors.unsupported_payloads, 1);
+        assert_eq!(state.devices[0].errors.ble_disconnects, 0);
+        assert_eq!(state.errors.ble_disconnects, 0);
+        assert_eq!(actions, vec![Action::Wait]);
+    }
+
+    #[test]
+    fn radon_is_tracked_per_device() {
+        const OTHER: u32 = 2930_654321;
+        let (state, _) = State::new(&[SERIAL, OTHER], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS), (OTHER, ADDRESS)])
+            .with_radon_reports(&[(OTHER, at(-60))])
+            .start(at(0));
+        // The other device already reported radon this hour, and only the
+        // first one's measurement has it.
+        let (state, actions) = state.step(
+            Event::MeasurementsRead {
+                measurements: vec![measurement()],
+                failed: vec![],
+                unsupported: vec![],
+            },
+            at(1),
+        );
+        assert_eq!(actions, vec![Action::SendMeasurement]);
+        let (state, actions) = state.step(
+            Event::MeasurementSent {
+                sent: vec![SinkKind::Http],
+                failed: vec![],
+                link_lost: false,
+                rejected: false,
+            },
+            at(2),
+        );
+        assert_eq!(
+            actions,
+            vec![Action::SaveRadonReport(vec![SERIAL], at(2)), Action::Wait]
+        );
+        assert_eq!(state.devices[0].last_radon, Some(at(2)));
+        assert_eq!(state.devices[1].last_radon, Some(at(-60)));
+
+        // An hour on, both are due.
+        let (_, actions) = state.step(Event::WaitElapsed, at(3600));
+        assert_eq!(
+            actions,
+            vec![Action::ReadWavePlus {
+                serials: vec![SERIAL, OTHER],
+                radon: vec![SERIAL, OTHER],
+            }]
+        );
+    }
+
+    #[test]
+    fn rescanning_unresolved_device_is_not_a_disconnect() {
+        const OTHER: u32 = 2930_654321;
+        let state = State::new(&[SERIAL, OTHER], RadonSchedule::default())
+            .with_cached_addresses(&[(SERIAL, ADDRESS)])
+            .with_mode(ExecutionMode::Wait);
+        let (state, actions) = state.step(Event::WaitElapsed, at(0));
+        assert_eq!(state.mode, ExecutionMode::Reinitialize);
+        assert_eq!(actions, vec![Action::ScanWavePlus]);
+
+        let (state, _) = state.step(Event::WavePlusFound(vec![]), at(1));
+        assert_eq!(state.mode, ExecutionMode::CollectMeasurement);
+        assert_eq!(state.errors.ble_disconnects, 0);
+        assert_eq!(state.devices[1].errors.not_found, 1);
+    }
+}
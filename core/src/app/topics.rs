@@ -0,0 +1,181 @@
+use serde_json::json;
+
+use crate::waveplus::measurement::LinkDiagnostics;
+use crate::waveplus::model::Model;
+
+/// Home Assistant device class and unit for each measurement field.
+fn sensor_class(field: &str) -> (Option<&'static str>, &'static str) {
+    match field {
+        "humidity" => (Some("humidity"), "%"),
+        "radon_short" | "radon_long" => (None, "Bq/m³"),
+        "temperature" => (Some("temperature"), "°C"),
+        "pressure" => (Some("atmospheric_pressure"), "hPa"),
+        "co2" => (Some("carbon_dioxide"), "ppm"),
+        "voc" => (Some("volatile_organic_compounds_parts"), "ppb"),
+        "pm1" => (Some("pm1"), "µg/m³"),
+        "pm2_5" => (Some("pm25"), "µg/m³"),
+        "light" => (None, "%"),
+        "battery" => (Some("battery"), "%"),
+        "battery_low" => (Some("battery"), ""),
+        "battery_voltage" => (Some("voltage"), "V"),
+        "connection_rssi" => (Some("signal_strength"), "dBm"),
+        "connect_ms" | "discover_ms" | "read_ms" => (Some("duration"), "ms"),
+        _ => (None, ""),
+    }
+}
+
+/// The Home Assistant component a field is discovered as. `battery_low` is
+/// published as `1` or `0`, which a binary sensor shows as low or normal.
+fn component(field: &str) -> &'static str {
+    match field {
+        "battery_low" => "binary_sensor",
+        _ => "sensor",
+    }
+}
+
+/// The fields describing the BLE link rather than the air, shown among
+/// the device's diagnostics.
+fn is_diagnostic(field: &str) -> bool {
+    LinkDiagnostics::FIELDS.contains(&field)
+}
+
+/// Every field of `model` to announce, its readings and the link
+/// diagnostics.
+pub fn fields(model: Model) -> impl Iterator<Item = &'static str> {
+    model
+        .fields()
+        .iter()
+        .copied()
+        .chain(LinkDiagnostics::FIELDS)
+}
+
+pub struct Topics<'a> {
+    pub prefix: &'a str,
+    pub discovery_prefix: &'a str,
+}
+
+impl Topics<'_> {
+    pub fn availability(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    pub fn state(&self, serial: u32, field: &str) -> String {
+        format!("{}/{}/{}", self.prefix, serial, field)
+    }
+
+    pub fn discovery(&self, serial: u32, field: &str) -> String {
+        format!(
+            "{}/{}/waveplus_{}/{}/config",
+            self.discovery_prefix,
+            component(field),
+            serial,
+            field
+        )
+    }
+
+    /// Retained Home Assistant discovery config for one field of a device.
+    pub fn discovery_config(&self, serial: u32, model: Model, field: &str) -> serde_json::Value {
+        let (device_class, unit) = sensor_class(field);
+        let mut config = json!({
+            "name": field.replace('_', " "),
+            "unique_id": format!("waveplus_{}_{}", serial, field),
+            "state_topic": self.state(serial, field),
+            "availability_topic": self.availability(),
+            "device": {
+                "identifiers": [format!("waveplus_{}", serial)],
+                "name": format!("{} {}", model.name(), serial),
+                "manufacturer": "Airthings",
+                "model": model.name(),
+                "serial_number": serial.to_string(),
+            },
+        });
+        if component(field) == "binary_sensor" {
+            config["payload_on"] = json!("1");
+            config["payload_off"] = json!("0");
+        } else {
+            config["state_class"] = json!("measurement");
+            if !unit.is_empty() {
+                config["unit_of_measurement"] = json!(unit);
+            }
+        }
+        if let Some(device_class) = device_class {
+            config["device_class"] = json!(device_class);
+        }
+        if is_diagnostic(field) {
+            config["entity_category"] = json!("diagnostic");
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPICS: Topics = Topics {
+        prefix: "waveplus",
+        discovery_prefix: "homeassistant",
+    };
+
+    #[test]
+    fn discovers_numeric_fields_as_sensors() {
+        assert_eq!(
+            TOPICS.discovery(2930123456, "co2"),
+            "homeassistant/sensor/waveplus_2930123456/co2/config"
+        );
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "co2");
+        assert_eq!(config["state_topic"], "waveplus/2930123456/co2");
+        assert_eq!(config["device_class"], "carbon_dioxide");
+        assert_eq!(config["unit_of_measurement"], "ppm");
+        assert_eq!(config["state_class"], "measurement");
+
+        // Without a unit Home Assistant would take the counter for text.
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "waves");
+        assert!(config.get("unit_of_measurement").is_none());
+        assert_eq!(config["state_class"], "measurement");
+    }
+
+    #[test]
+    fn discovers_link_diagnostics() {
+        let announced: Vec<&str> = fields(Model::WaveMini).collect();
+        assert_eq!(
+            announced,
+            [
+                "humidity",
+                "temperature",
+                "voc",
+                "connection_rssi",
+                "connect_ms",
+                "discover_ms",
+                "read_ms"
+            ]
+        );
+
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "connection_rssi");
+        assert_eq!(config["device_class"], "signal_strength");
+        assert_eq!(config["unit_of_measurement"], "dBm");
+        assert_eq!(config["entity_category"], "diagnostic");
+
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "read_ms");
+        assert_eq!(config["device_class"], "duration");
+        assert_eq!(config["unit_of_measurement"], "ms");
+        assert_eq!(config["entity_category"], "diagnostic");
+
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "co2");
+        assert!(config.get("entity_category").is_none());
+    }
+
+    #[test]
+    fn discovers_battery_low_as_binary_sensor() {
+        assert_eq!(
+            TOPICS.discovery(2930123456, "battery_low"),
+            "homeassistant/binary_sensor/waveplus_2930123456/battery_low/config"
+        );
+        let config = TOPICS.discovery_config(2930123456, Model::WavePlus, "battery_low");
+        assert_eq!(config["device_class"], "battery");
+        assert_eq!(config["payload_on"], "1");
+        assert_eq!(config["payload_off"], "0");
+        assert!(config.get("unit_of_measurement").is_none());
+        assert!(config.get("state_class").is_none());
+    }
+}
@@ -0,0 +1,67 @@
+//! Comparison of firmware versions, as published in the update manifest.
+
+/// Whether the dotted numeric version `candidate` is newer than `current`.
+/// Missing components count as zero and any pre-release suffix is ignored.
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Vec<u64> {
+        version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    }
+    let candidate = parse(candidate);
+    let current = parse(current);
+    for i in 0..candidate.len().max(current.len()) {
+        let a = candidate.get(i).copied().unwrap_or(0);
+        let b = current.get(i).copied().unwrap_or(0);
+        if a != b {
+            return a > b;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_components_numerically() {
+        assert!(is_newer("0.2.0", "0.1.0"));
+        assert!(is_newer("0.10.0", "0.9.0"));
+        assert!(is_newer("1.0.0", "0.99.99"));
+        assert!(!is_newer("0.1.0", "0.2.0"));
+    }
+
+    #[test]
+    fn equal_versions_are_not_newer() {
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("", ""));
+    }
+
+    #[test]
+    fn ignores_v_prefix() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("0.1.0", "v0.1.0"));
+    }
+
+    #[test]
+    fn ignores_pre_release_and_build_suffixes() {
+        assert!(!is_newer("0.1.0-rc.1", "0.1.0"));
+        assert!(!is_newer("0.1.0+build.5", "0.1.0"));
+        assert!(is_newer("0.2.0-beta", "0.1.0"));
+    }
+
+    #[test]
+    fn missing_components_count_as_zero() {
+        assert!(!is_newer("0.2", "0.2.0"));
+        assert!(!is_newer("0.2.0", "0.2"));
+        assert!(is_newer("0.2.1", "0.2"));
+        assert!(is_newer("1", "0.9.9"));
+    }
+}
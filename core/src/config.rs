@@ -0,0 +1,388 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+use crate::app::auth::{parse_headers, Auth};
+use crate::app::payload::PayloadFormat;
+use crate::app::sink::parse_sinks;
+
+/// Version of the [`DeviceConfig`] schema, bump this when renaming or
+/// changing the type of a field and add a step to [`migrate`].  Added
+/// fields are filled in from the defaults without a version bump.
+pub const VERSION: u32 = 2;
+
+/// Configuration stored in NVS so that it can be changed without
+/// reflashing.  On first boot it is seeded from `cfg.toml`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub version: u32,
+    pub wifi_ssid: String,
+    pub wifi_psk: String,
+    pub waveplus_serials: Vec<u32>,
+    pub read_interval: u16,
+    pub radon_interval: u32,
+    pub radon_offset: u32,
+    pub scan_interval: u16,
+    pub scan_window: u16,
+    pub scan_timeout: u32,
+    pub min_read_interval: u32,
+    pub queue_capacity: u16,
+    pub batch_size: u16,
+    pub batch_max_bytes: u32,
+    pub server: String,
+    pub output_format: String,
+    pub sinks: String,
+    pub influx_org: String,
+    pub influx_bucket: String,
+    pub influx_token: String,
+    pub auth_type: String,
+    pub auth_token: String,
+    pub auth_username: String,
+    pub auth_password: String,
+    pub http_headers: String,
+    pub signing_secret: String,
+    pub ntp_server: String,
+    pub http_retries: u32,
+    pub http_retry_delay: u32,
+    pub http_port: u16,
+    pub ota_url: String,
+    pub ota_interval: u32,
+    pub mqtt_url: String,
+    pub mqtt_client_id: String,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    pub mqtt_prefix: String,
+    pub mqtt_discovery_prefix: String,
+}
+
+/// Credentials and header values are redacted, only whether they are set is
+/// shown.
+impl fmt::Debug for DeviceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceConfig")
+            .field("version", &self.version)
+            .field("wifi_ssid", &self.wifi_ssid)
+            .field("wifi_psk", &redacted(&self.wifi_psk))
+            .field("waveplus_serials", &self.waveplus_serials)
+            .field("read_interval", &self.read_interval)
+            .field("radon_interval", &self.radon_interval)
+            .field("radon_offset", &self.radon_offset)
+            .field("scan_interval", &self.scan_interval)
+            .field("scan_window", &self.scan_window)
+            .field("scan_timeout", &self.scan_timeout)
+            .field("min_read_interval", &self.min_read_interval)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("batch_size", &self.batch_size)
+            .field("batch_max_bytes", &self.batch_max_bytes)
+            .field("server", &self.server)
+            .field("output_format", &self.output_format)
+            .field("sinks", &self.sinks)
+            .field("influx_org", &self.influx_org)
+            .field("influx_bucket", &self.influx_bucket)
+            .field("influx_token", &redacted(&self.influx_token))
+            .field("auth_type", &self.auth_type)
+            .field("auth_token", &redacted(&self.auth_token))
+            .field("auth_username", &self.auth_username)
+            .field("auth_password", &redacted(&self.auth_password))
+            .field("http_headers", &redacted(&self.http_headers))
+            .field("signing_secret", &redacted(&self.signing_secret))
+            .field("ntp_server", &self.ntp_server)
+            .field("http_retries", &self.http_retries)
+            .field("http_retry_delay", &self.http_retry_delay)
+            .field("http_port", &self.http_port)
+            .field("ota_url", &self.ota_url)
+            .field("ota_interval", &self.ota_interval)
+            .field("mqtt_url", &self.mqtt_url)
+            .field("mqtt_client_id", &self.mqtt_client_id)
+            .field("mqtt_username", &self.mqtt_username)
+            .field("mqtt_password", &redacted(&self.mqtt_password))
+            .field("mqtt_prefix", &self.mqtt_prefix)
+            .field("mqtt_discovery_prefix", &self.mqtt_discovery_prefix)
+            .finish()
+    }
+}
+
+fn redacted(value: &str) -> &'static str {
+    if value.is_empty() {
+        ""
+    } else {
+        "<redacted>"
+    }
+}
+
+pub fn parse_serials(serials: &str) -> Result<Vec<u32>> {
+    serials
+        .split(',')
+        .map(str::trim)
+        .filter(|serial| !serial.is_empty())
+        .map(|serial| {
+            serial
+                .parse::<u32>()
+                .map_err(|err| anyhow!("Invalid Wave Plus serial {:?}: {}", serial, err))
+        })
+        .collect()
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.read_interval == 0 {
+            bail!("read_interval must be greater than zero");
+        }
+        if self.radon_interval == 0 {
+            bail!("radon_interval must be greater than zero");
+        }
+        if self.radon_offset >= 60 {
+            bail!("radon_offset must be less than 60 minutes");
+        }
+        if self.scan_window == 0 || self.scan_window > self.scan_interval {
+            bail!("scan_window must be between 1 and scan_interval");
+        }
+        if self.scan_timeout == 0 {
+            bail!("scan_timeout must be greater than zero");
+        }
+        if self.scan_timeout > i32::MAX as u32 {
+            bail!("scan_timeout must be at most {} milliseconds", i32::MAX);
+        }
+        if self.queue_capacity == 0 {
+            bail!("queue_capacity must be greater than zero");
+        }
+        if self.batch_size == 0 {
+            bail!("batch_size must be greater than zero");
+        }
+        if !self.server.is_empty()
+            && !self.server.starts_with("http://")
+            && !self.server.starts_with("https://")
+        {
+            bail!("server must be an http or https URL: {:?}", self.server);
+        }
+        if !self.ota_url.is_empty() && self.ota_interval == 0 {
+            bail!("ota_interval must be greater than zero");
+        }
+        self.output_format.parse::<PayloadFormat>()?;
+        parse_sinks(&self.sinks)?;
+        self.auth()?;
+        parse_headers(&self.http_headers)?;
+        Ok(())
+    }
+
+    /// Whether there is a network to join and a sensor to read. Without
+    /// them the device starts the provisioning access point.
+    pub fn is_provisioned(&self) -> bool {
+        !self.wifi_ssid.is_empty() && !self.waveplus_serials.is_empty()
+    }
+
+    /// The credentials to upload with.
+    pub fn auth(&self) -> Result<Auth> {
+        Auth::new(
+            &self.auth_type,
+            &self.auth_token,
+            &self.auth_username,
+            &self.auth_password,
+        )
+    }
+}
+
+/// Bring a stored configuration up to [`VERSION`], taking any fields it
+/// lacks from `defaults`.
+pub fn migrate(stored: Value, defaults: &DeviceConfig) -> Result<DeviceConfig> {
+    let Value::Object(mut stored) = stored else {
+        bail!("Stored configuration is not an object");
+    };
+
+    let version = stored.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+    if version > VERSION {
+        bail!(
+            "Stored configuration version {} is newer than {}",
+            version,
+            VERSION
+        );
+    }
+    // Version 0 is a configuration written without a version, which has
+    // the same fields as version 1.
+    if version < 2 {
+        // Version 1 spaced out reads with `read_mode = "passive"` and
+        // `gatt_fallback_interval`.
+        let read_mode = stored.remove("read_mode");
+        let interval = stored.remove("gatt_fallback_interval");
+        if read_mode.as_ref().and_then(Value::as_str) == Some("passive") {
+            if let Some(interval) = interval {
+                stored.insert("min_read_interval".to_string(), interval);
+            }
+        }
+    }
+    stored.insert("version".to_string(), Value::from(VERSION));
+
+    let Value::Object(defaults) = serde_json::to_value(defaults)? else {
+        bail!("Default configuration is not an object");
+    };
+    for (key, value) in defaults {
+        stored.entry(key).or_insert(value);
+    }
+
+    Ok(serde_json::from_value(Value::Object(stored))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The defaults of `cfg.toml`.
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            version: VERSION,
+            wifi_ssid: String::new(),
+            wifi_psk: String::new(),
+            waveplus_serials: Vec::new(),
+            read_interval: 30,
+            radon_interval: 60,
+            radon_offset: 0,
+            scan_interval: 100,
+            scan_window: 99,
+            scan_timeout: 10000,
+            min_read_interval: 0,
+            queue_capacity: 256,
+            batch_size: 10,
+            batch_max_bytes: 8192,
+            server: String::new(),
+            output_format: "json".to_string(),
+            sinks: "http,mqtt".to_string(),
+            influx_org: String::new(),
+            influx_bucket: String::new(),
+            influx_token: String::new(),
+            auth_type: "none".to_string(),
+            auth_token: String::new(),
+            auth_username: String::new(),
+            auth_password: String::new(),
+            http_headers: String::new(),
+            signing_secret: String::new(),
+            ntp_server: "pool.ntp.org".to_string(),
+            http_retries: 3,
+            http_retry_delay: 1000,
+            http_port: 80,
+            ota_url: String::new(),
+            ota_interval: 86400,
+            mqtt_url: String::new(),
+            mqtt_client_id: "waveplus-reader".to_string(),
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_prefix: "waveplus".to_string(),
+            mqtt_discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let config = DeviceConfig {
+            wifi_ssid: "home".to_string(),
+            wifi_psk: "wifi-hunter2".to_string(),
+            influx_token: "influx-hunter2".to_string(),
+            auth_token: "token-hunter2".to_string(),
+            auth_password: "password-hunter2".to_string(),
+            http_headers: "X-API-Key: header-hunter2".to_string(),
+            signing_secret: "signing-hunter2".to_string(),
+            mqtt_password: "mqtt-hunter2".to_string(),
+            ..config()
+        };
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(debug.contains(r#"wifi_ssid: "home""#));
+        assert!(debug.contains(r#"wifi_psk: "<redacted>""#));
+        assert!(debug.contains(r#"signing_secret: "<redacted>""#));
+    }
+
+    #[test]
+    fn migrates_passive_read_mode() {
+        let mut stored = serde_json::to_value(config()).unwrap();
+        let fields = stored.as_object_mut().unwrap();
+        fields.insert("version".to_string(), Value::from(1));
+        fields.remove("min_read_interval");
+        fields.insert("read_mode".to_string(), Value::from("passive"));
+        fields.insert("gatt_fallback_interval".to_string(), Value::from(45));
+        let migrated = migrate(stored, &config()).unwrap();
+        assert_eq!(migrated.version, VERSION);
+        assert_eq!(migrated.min_read_interval, 45);
+
+        let mut stored = serde_json::to_value(config()).unwrap();
+        let fields = stored.as_object_mut().unwrap();
+        fields.insert("version".to_string(), Value::from(1));
+        fields.remove("min_read_interval");
+        fields.insert("read_mode".to_string(), Value::from("gatt"));
+        fields.insert("gatt_fallback_interval".to_string(), Value::from(45));
+        assert_eq!(migrate(stored, &config()).unwrap().min_read_interval, 0);
+    }
+
+    #[test]
+    fn rejects_scan_timeout_out_of_range() {
+        let zero = DeviceConfig {
+            scan_timeout: 0,
+            ..config()
+        };
+        assert_eq!(
+            zero.validate().unwrap_err().to_string(),
+            "scan_timeout must be greater than zero"
+        );
+        let too_long = DeviceConfig {
+            scan_timeout: i32::MAX as u32 + 1,
+            ..config()
+        };
+        assert_eq!(
+            too_long.validate().unwrap_err().to_string(),
+            "scan_timeout must be at most 2147483647 milliseconds"
+        );
+    }
+
+    #[test]
+    fn provisioned_needs_network_and_serial() {
+        assert!(!config().is_provisioned());
+        let network_only = DeviceConfig {
+            wifi_ssid: "home".to_string(),
+            ..config()
+        };
+        assert!(!network_only.is_provisioned());
+        let provisioned = DeviceConfig {
+            waveplus_serials: vec![2930123456],
+            ..network_only
+        };
+        assert!(provisioned.is_provisioned());
+    }
+
+    #[test]
+    fn migrate_fills_missing_keys_from_defaults() {
+        let mut stored = serde_json::to_value(DeviceConfig {
+            mqtt_prefix: "air".to_string(),
+            ..config()
+        })
+        .unwrap();
+        stored.as_object_mut().unwrap().remove("ntp_server");
+        let defaults = DeviceConfig {
+            ntp_server: "time.example.com".to_string(),
+            ..config()
+        };
+        let migrated = migrate(stored, &defaults).unwrap();
+        assert_eq!(migrated.ntp_server, "time.example.com");
+        assert_eq!(migrated.mqtt_prefix, "air");
+    }
+
+    #[test]
+    fn migrate_upgrades_unversioned_config() {
+        let mut stored = serde_json::to_value(config()).unwrap();
+        stored.as_object_mut().unwrap().remove("version");
+        assert_eq!(migrate(stored, &config()).unwrap().version, VERSION);
+    }
+
+    #[test]
+    fn migrate_rejects_newer_version() {
+        let mut stored = serde_json::to_value(config()).unwrap();
+        stored["version"] = Value::from(VERSION + 1);
+        assert!(migrate(stored, &config()).is_err());
+    }
+
+    #[test]
+    fn migrate_rejects_bad_types() {
+        let mut stored = serde_json::to_value(config()).unwrap();
+        stored["read_interval"] = Value::from("thirty");
+        assert!(migrate(stored, &config()).is_err());
+        assert!(migrate(Value::from("config"), &config()).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! The measurement loop's state machine, payload encodings and the Wave
+//! Plus data formats, which perform no I/O of their own. The firmware
+//! provides the BLE, Wi-Fi, NVS and HTTP implementations of
+//! [`app::effects`].
+
+pub mod app;
+pub mod config;
+pub mod provision;
+pub mod utils;
+pub mod waveplus;
@@ -0,0 +1,136 @@
+use std::net::Ipv4Addr;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// Answer a DNS query with `ip` for every A record, so that clients of the
+/// access point are directed to the provisioning form.  Other record types
+/// get an empty answer.  Returns `None` for packets that are not a single
+/// well formed question.
+pub fn response(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+    let is_query = query[2] & 0x80 == 0;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if !is_query || qdcount != 1 {
+        return None;
+    }
+
+    // Skip the labels of the queried name up to the terminating zero.
+    let mut end = HEADER_LEN;
+    loop {
+        let len = usize::from(*query.get(end)?);
+        if len == 0 {
+            end += 1;
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+    }
+    let question = query.get(HEADER_LEN..end + 4)?;
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let qclass = u16::from_be_bytes([question[question.len() - 2], question[question.len() - 1]]);
+    let answer = qtype == TYPE_A && qclass == CLASS_IN;
+
+    let mut response = Vec::with_capacity(end + 20);
+    response.extend_from_slice(&query[0..2]);
+    // Response, recursion desired and available, no error.
+    response.extend_from_slice(&[0x81, 0x80]);
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&u16::from(answer).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    if answer {
+        // Pointer to the name in the question.
+        response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&60u32.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&ip.octets());
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// A query for `example.com` with id 0x1234, recursion desired.
+    fn query(qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn answers_a_queries_with_the_access_point() {
+        let query = query(TYPE_A);
+        let response = response(&query, IP).unwrap();
+        assert_eq!(response[0..2], [0x12, 0x34]);
+        assert_eq!(response[2..4], [0x81, 0x80]);
+        // One question, one answer.
+        assert_eq!(response[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(response[HEADER_LEN..query.len()], query[HEADER_LEN..]);
+        assert_eq!(
+            response[query.len()..],
+            [0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]
+        );
+    }
+
+    #[test]
+    fn answers_other_types_with_no_records() {
+        const TYPE_AAAA: u16 = 28;
+        let query = query(TYPE_AAAA);
+        let response = response(&query, IP).unwrap();
+        assert_eq!(response[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn ignores_short_and_truncated_queries() {
+        let query = query(TYPE_A);
+        for len in 0..query.len() {
+            assert_eq!(response(&query[..len], IP), None, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn ignores_malformed_queries() {
+        let mut reply = query(TYPE_A);
+        reply[2] |= 0x80;
+        assert_eq!(response(&reply, IP), None);
+
+        let mut two_questions = query(TYPE_A);
+        two_questions[5] = 2;
+        assert_eq!(response(&two_questions, IP), None);
+
+        // Compression pointers don't belong in a question.
+        let mut pointer = query(TYPE_A);
+        pointer[HEADER_LEN] = 0xc0;
+        assert_eq!(response(&pointer, IP), None);
+
+        // A label running past the end of the packet.
+        let mut overlong = query(TYPE_A);
+        overlong[HEADER_LEN] = 0x3f;
+        assert_eq!(response(&overlong, IP), None);
+
+        // Every byte value in every position, for panics.
+        let query = query(TYPE_A);
+        for i in 0..query.len() {
+            for byte in 0..=u8::MAX {
+                let mut mangled = query.clone();
+                mangled[i] = byte;
+                let _ = response(&mangled, IP);
+            }
+        }
+    }
+}
@@ -0,0 +1,32 @@
+pub mod time {
+    use std::time::SystemTime;
+    use time::*;
+
+    pub fn get_datetime() -> Result<PrimitiveDateTime> {
+        let unixtime = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        get_local_datetime(unixtime.as_secs() as i64)
+    }
+
+    /// The local date and time of a unix timestamp.
+    #[cfg(target_os = "espidf")]
+    pub fn get_local_datetime(unixtime: i64) -> Result<PrimitiveDateTime> {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::localtime_r(&unixtime, &mut tm) };
+        let month = Month::try_from(1u8 + tm.tm_mon as u8)?;
+        let date = Date::from_calendar_date(1900 + tm.tm_year, month, tm.tm_mday as _)?;
+        let time = Time::from_hms(tm.tm_hour as _, tm.tm_min as _, tm.tm_sec as _)?;
+
+        Ok(PrimitiveDateTime::new(date, time))
+    }
+
+    /// Off the device, e.g. in tests, no time zone is configured and local
+    /// time is UTC.
+    #[cfg(not(target_os = "espidf"))]
+    pub fn get_local_datetime(unixtime: i64) -> Result<PrimitiveDateTime> {
+        let at = OffsetDateTime::from_unix_timestamp(unixtime)?;
+        Ok(PrimitiveDateTime::new(at.date(), at.time()))
+    }
+}
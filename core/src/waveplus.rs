@@ -0,0 +1,12 @@
+macro_rules! bincode_options {
+    () => {
+        bincode::DefaultOptions::new()
+            .with_little_endian()
+            .with_no_limit()
+            .with_fixint_encoding()
+    };
+}
+
+pub mod address;
+pub mod measurement;
+pub mod model;
@@ -0,0 +1,43 @@
+use std::fmt;
+
+/// The BLE address of a device, kept apart from the BLE stack's own type so
+/// that measurements and the state machine don't depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    /// The address bytes, least significant first as sent over the air.
+    pub bytes: [u8; 6],
+    /// The NimBLE address type: 0 public, 1 random, 2 public identity or 3
+    /// random identity.
+    pub kind: u8,
+}
+
+impl Address {
+    /// The highest known address type.
+    const MAX_KIND: u8 = 3;
+
+    /// The address bytes followed by its type, as cached in NVS.
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let mut blob = [0u8; 7];
+        blob[..6].copy_from_slice(&self.bytes);
+        blob[6] = self.kind;
+        blob
+    }
+
+    pub fn from_bytes(blob: &[u8]) -> Option<Address> {
+        let bytes: [u8; 6] = blob.get(..6)?.try_into().ok()?;
+        let kind = *blob.get(6)?;
+        (blob.len() == 7 && kind <= Address::MAX_KIND).then_some(Address { bytes, kind })
+    }
+}
+
+/// Most significant byte first, as printed on device labels.
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [b0, b1, b2, b3, b4, b5] = self.bytes;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            b5, b4, b3, b2, b1, b0
+        )
+    }
+}
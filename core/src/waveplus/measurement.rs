@@ -0,0 +1,628 @@
+use anyhow::{anyhow, Result};
+use core::str;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use time::{format_description, OffsetDateTime, PrimitiveDateTime};
+
+use crate::utils::time::{get_datetime, get_local_datetime};
+use crate::waveplus::address::Address;
+use crate::waveplus::model::Model;
+
+#[derive(Debug, Deserialize)]
+pub struct WavePlusManufacturerInfo {
+    pub serial_number: u32,
+    pub _unknown: u16,
+}
+
+/// The only Wave Plus payload layout we know how to decode.
+pub const WAVE_PLUS_VERSION: u8 = 1;
+
+#[derive(Debug, Deserialize)]
+pub struct WavePlusRawMeasurementData {
+    version: u8,
+    humidity: u8,
+    ambient_light: u8,
+    waves: u8,
+    radon_short: u16,
+    radon_long: u16,
+    temperature: u16,
+    pressure: u16,
+    co2: u16,
+    voc: u16,
+    _unknown2: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaveRadonRawMeasurementData {
+    version: u8,
+    humidity: u8,
+    _unknown1: u16,
+    radon_short: u16,
+    radon_long: u16,
+    temperature: u16,
+    _unknown2: [u16; 5],
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaveMiniRawMeasurementData {
+    _unknown1: u16,
+    temperature: u16,
+    _unknown2: u16,
+    humidity: u16,
+    voc: u16,
+    _unknown3: u16,
+    _unknown4: u32,
+    _unknown5: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ViewPlusRawMeasurementData {
+    version: u8,
+    humidity: u8,
+    _unknown1: u16,
+    radon_short: u16,
+    radon_long: u16,
+    temperature: u16,
+    pressure: u16,
+    co2: u16,
+    voc: u16,
+    pm1: u16,
+    pm2_5: u16,
+    _unknown2: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct WavePlusMeasurementData {
+    version: u8,
+    humidity: f64,
+    /// Ambient light level in percent.
+    light: f64,
+    /// Activity counter behind the "waves" shown in the Airthings app.
+    waves: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_short: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_long: Option<f64>,
+    temperature: f64,
+    pressure: f64,
+    co2: f64,
+    voc: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct WaveRadonMeasurementData {
+    version: u8,
+    humidity: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_short: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_long: Option<f64>,
+    temperature: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct WaveMiniMeasurementData {
+    humidity: f64,
+    temperature: f64,
+    voc: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ViewPlusMeasurementData {
+    version: u8,
+    humidity: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_short: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radon_long: Option<f64>,
+    temperature: f64,
+    pressure: f64,
+    co2: f64,
+    voc: f64,
+    pm1: f64,
+    pm2_5: f64,
+}
+
+/// The readings of one of the supported models, serialized as the fields
+/// of that model.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum MeasurementData {
+    WavePlus(WavePlusMeasurementData),
+    WaveRadon(WaveRadonMeasurementData),
+    WaveMini(WaveMiniMeasurementData),
+    ViewPlus(ViewPlusMeasurementData),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeasurementMetadata {
+    pub serial_number: u32,
+    pub model: Model,
+    pub address: Address,
+    pub datetime: PrimitiveDateTime,
+    pub timestamp: OffsetDateTime,
+}
+
+impl Serialize for MeasurementMetadata {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("MeasurementMetadata", 4)?;
+
+        let serial = self.serial_number.to_string();
+        state.serialize_field("serial_number", &serial)?;
+
+        state.serialize_field("model", self.model.name())?;
+
+        let address = self.address.to_string();
+        state.serialize_field("address", &address)?;
+
+        let format = format_description::parse_borrowed::<1>(
+            "[year]-[month]-[day] [hour]:[minute]:[second]",
+        )
+        .expect("Failed to format time");
+        let datetime = self
+            .datetime
+            .format(&format)
+            .expect("Failed to format time");
+        state.serialize_field("datetime", &datetime)?;
+
+        state.end()
+    }
+}
+
+/// Below this estimated level the battery is reported as low.
+const LOW_BATTERY_PERCENT: f64 = 10.0;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Battery {
+    pub voltage: f64,
+    pub percentage: f64,
+    pub low: bool,
+}
+
+impl Battery {
+    /// Estimate the remaining charge of the two AA cells from their
+    /// voltage, using the discharge curve of alkaline batteries.
+    pub fn from_voltage(voltage: f64) -> Battery {
+        // (voltage, percentage) points, linearly interpolated in between.
+        const CURVE: [(f64, f64); 6] = [
+            (2.10, 0.0),
+            (2.20, 5.0),
+            (2.50, 28.0),
+            (2.60, 53.0),
+            (2.80, 81.0),
+            (3.00, 100.0),
+        ];
+        let percentage = if voltage <= CURVE[0].0 {
+            0.0
+        } else {
+            CURVE
+                .windows(2)
+                .find(|points| voltage < points[1].0)
+                .map(|points| {
+                    let ((v0, p0), (v1, p1)) = (points[0], points[1]);
+                    p0 + (voltage - v0) / (v1 - v0) * (p1 - p0)
+                })
+                .unwrap_or(100.0)
+        };
+        Battery {
+            voltage,
+            percentage: percentage.round(),
+            low: percentage < LOW_BATTERY_PERCENT,
+        }
+    }
+}
+
+/// Quality of the BLE link a measurement was read over, to tell range
+/// problems apart from other failures.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct LinkDiagnostics {
+    /// The RSSI of the GATT connection, not of the scan that found the
+    /// device. Queue entries written before the rename call it `rssi`.
+    #[serde(skip_serializing_if = "Option::is_none", alias = "rssi")]
+    pub connection_rssi: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discover_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_ms: Option<u32>,
+}
+
+impl LinkDiagnostics {
+    /// The names of the fields, reported by every model.
+    pub const FIELDS: [&'static str; 4] =
+        ["connection_rssi", "connect_ms", "discover_ms", "read_ms"];
+
+    pub fn is_empty(&self) -> bool {
+        *self == LinkDiagnostics::default()
+    }
+
+    fn fields(&self) -> Vec<(&'static str, f64)> {
+        Self::FIELDS
+            .into_iter()
+            .zip([
+                self.connection_rssi.map(f64::from),
+                self.connect_ms.map(f64::from),
+                self.discover_ms.map(f64::from),
+                self.read_ms.map(f64::from),
+            ])
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub metadata: MeasurementMetadata,
+    pub data: MeasurementData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<Battery>,
+    #[serde(skip_serializing_if = "LinkDiagnostics::is_empty")]
+    pub link: LinkDiagnostics,
+}
+
+impl Measurement {
+    pub fn new(
+        serial_number: u32,
+        model: Model,
+        address: Address,
+        mut data: MeasurementData,
+        include_radon: bool,
+    ) -> Self {
+        if !include_radon {
+            log::warn!("Not returning radon measurement");
+            data.clear_radon();
+        }
+        let datetime = get_datetime().expect("Unable to get current date and time");
+        let metadata = MeasurementMetadata {
+            serial_number,
+            model,
+            address,
+            datetime,
+            timestamp: OffsetDateTime::now_utc(),
+        };
+        Measurement {
+            metadata,
+            data,
+            battery: None,
+            link: LinkDiagnostics::default(),
+        }
+    }
+
+    /// A measurement taken earlier, at `timestamp`.
+    pub fn logged(
+        serial_number: u32,
+        model: Model,
+        address: Address,
+        data: MeasurementData,
+        timestamp: OffsetDateTime,
+    ) -> Result<Self> {
+        let metadata = MeasurementMetadata {
+            serial_number,
+            model,
+            address,
+            datetime: get_local_datetime(timestamp.unix_timestamp())?,
+            timestamp,
+        };
+        Ok(Measurement {
+            metadata,
+            data,
+            battery: None,
+            link: LinkDiagnostics::default(),
+        })
+    }
+
+    pub fn has_radon(&self) -> bool {
+        self.data.radon().0.is_some()
+    }
+
+    /// The fields of the readings followed by the battery and link
+    /// diagnostics, if known.
+    pub fn fields(&self) -> Vec<(&'static str, f64)> {
+        let mut fields = self.data.fields();
+        if let Some(battery) = self.battery {
+            fields.extend([
+                ("battery", battery.percentage),
+                ("battery_voltage", battery.voltage),
+                ("battery_low", if battery.low { 1.0 } else { 0.0 }),
+            ]);
+        }
+        fields.extend(self.link.fields());
+        fields
+    }
+}
+
+/// A measurement as kept in the upload queue. Unlike the payloads, it keeps
+/// everything needed to encode the measurement in any output format, so
+/// that queued measurements survive a change of `output_format`.
+#[derive(Serialize, Deserialize)]
+struct QueueEntry {
+    serial_number: u32,
+    address: [u8; 7],
+    timestamp: i128,
+    data: serde_json::Value,
+    battery: Option<Battery>,
+    link: LinkDiagnostics,
+}
+
+impl Measurement {
+    /// Encode for the upload queue, see [`Measurement::from_queue_entry`].
+    pub fn to_queue_entry(&self) -> Result<Vec<u8>> {
+        let metadata = &self.metadata;
+        Ok(serde_json::to_vec(&QueueEntry {
+            serial_number: metadata.serial_number,
+            address: metadata.address.to_bytes(),
+            timestamp: metadata.timestamp.unix_timestamp_nanos(),
+            data: serde_json::to_value(self.data)?,
+            battery: self.battery,
+            link: self.link,
+        })?)
+    }
+
+    pub fn from_queue_entry(entry: &[u8]) -> Result<Measurement> {
+        let entry: QueueEntry = serde_json::from_slice(entry)?;
+        let model = Model::from_serial(entry.serial_number);
+        // The serialized readings aren't tagged with their model, so they
+        // are read back as the model the serial number belongs to.
+        let data = match model {
+            Model::WavePlus => MeasurementData::WavePlus(serde_json::from_value(entry.data)?),
+            Model::WaveRadon => MeasurementData::WaveRadon(serde_json::from_value(entry.data)?),
+            Model::WaveMini => MeasurementData::WaveMini(serde_json::from_value(entry.data)?),
+            Model::ViewPlus => MeasurementData::ViewPlus(serde_json::from_value(entry.data)?),
+        };
+        let address = Address::from_bytes(&entry.address)
+            .ok_or_else(|| anyhow!("Invalid address {:?}", entry.address))?;
+        let timestamp = OffsetDateTime::from_unix_timestamp_nanos(entry.timestamp)?;
+        Ok(Measurement {
+            battery: entry.battery,
+            link: entry.link,
+            ..Measurement::logged(entry.serial_number, model, address, data, timestamp)?
+        })
+    }
+}
+
+impl MeasurementData {
+    /// The name and value of each field that is present, radon being
+    /// omitted when it was not requested or is not yet available.
+    pub fn fields(&self) -> Vec<(&'static str, f64)> {
+        let (radon_short, radon_long) = self.radon();
+        let mut fields = vec![("humidity", self.humidity())];
+        if let Some(radon_short) = radon_short {
+            fields.push(("radon_short", radon_short));
+        }
+        if let Some(radon_long) = radon_long {
+            fields.push(("radon_long", radon_long));
+        }
+        match self {
+            MeasurementData::WavePlus(data) => fields.extend([
+                ("temperature", data.temperature),
+                ("pressure", data.pressure),
+                ("co2", data.co2),
+                ("voc", data.voc),
+                ("light", data.light),
+                ("waves", data.waves),
+            ]),
+            MeasurementData::WaveRadon(data) => fields.push(("temperature", data.temperature)),
+            MeasurementData::WaveMini(data) => {
+                fields.extend([("temperature", data.temperature), ("voc", data.voc)])
+            }
+            MeasurementData::ViewPlus(data) => fields.extend([
+                ("temperature", data.temperature),
+                ("pressure", data.pressure),
+                ("co2", data.co2),
+                ("voc", data.voc),
+                ("pm1", data.pm1),
+                ("pm2_5", data.pm2_5),
+            ]),
+        }
+        fields
+    }
+
+    fn humidity(&self) -> f64 {
+        match self {
+            MeasurementData::WavePlus(data) => data.humidity,
+            MeasurementData::WaveRadon(data) => data.humidity,
+            MeasurementData::WaveMini(data) => data.humidity,
+            MeasurementData::ViewPlus(data) => data.humidity,
+        }
+    }
+
+    fn radon(&self) -> (Option<f64>, Option<f64>) {
+        match self {
+            MeasurementData::WavePlus(data) => (data.radon_short, data.radon_long),
+            MeasurementData::WaveRadon(data) => (data.radon_short, data.radon_long),
+            MeasurementData::WaveMini(_) => (None, None),
+            MeasurementData::ViewPlus(data) => (data.radon_short, data.radon_long),
+        }
+    }
+
+    fn set_radon(&mut self, radon_short: Option<f64>, radon_long: Option<f64>) {
+        let fields = match self {
+            MeasurementData::WavePlus(data) => (&mut data.radon_short, &mut data.radon_long),
+            MeasurementData::WaveRadon(data) => (&mut data.radon_short, &mut data.radon_long),
+            MeasurementData::WaveMini(_) => return,
+            MeasurementData::ViewPlus(data) => (&mut data.radon_short, &mut data.radon_long),
+        };
+        *fields.0 = radon_short;
+        *fields.1 = radon_long;
+    }
+
+    fn clear_radon(&mut self) {
+        self.set_radon(None, None);
+    }
+
+    /// Fill in radon from `previous` when this measurement has none.
+    pub fn keep_radon(&mut self, previous: &MeasurementData) {
+        if self.radon() == (None, None) {
+            let (radon_short, radon_long) = previous.radon();
+            self.set_radon(radon_short, radon_long);
+        }
+    }
+}
+
+/// A reading in a payload version this firmware doesn't know how to
+/// decode. Unlike other failures it means the device was reached, so it is
+/// told apart to keep the device's address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedVersion {
+    pub model: Model,
+    pub version: u8,
+}
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported {} payload version {}",
+            self.model.name(),
+            self.version
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+fn parse_radon(value: u16) -> Option<f64> {
+    if value > 16383 {
+        return None;
+    }
+    Some(f64::from(value))
+}
+
+impl TryFrom<&WavePlusRawMeasurementData> for WavePlusMeasurementData {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: &WavePlusRawMeasurementData) -> Result<WavePlusMeasurementData> {
+        if raw.version != WAVE_PLUS_VERSION {
+            return Err(UnsupportedVersion {
+                model: Model::WavePlus,
+                version: raw.version,
+            }
+            .into());
+        }
+        let radon_short = parse_radon(raw.radon_short);
+        let radon_long = parse_radon(raw.radon_long);
+
+        Ok(WavePlusMeasurementData {
+            version: raw.version,
+            humidity: f64::from(raw.humidity) / 2.0,
+            light: f64::from(raw.ambient_light) / 255.0 * 100.0,
+            waves: f64::from(raw.waves),
+            radon_short,
+            radon_long,
+            temperature: f64::from(raw.temperature) / 100.0,
+            pressure: f64::from(raw.pressure) / 50.0,
+            co2: f64::from(raw.co2),
+            voc: f64::from(raw.voc),
+        })
+    }
+}
+
+impl From<&WaveRadonRawMeasurementData> for WaveRadonMeasurementData {
+    fn from(raw: &WaveRadonRawMeasurementData) -> WaveRadonMeasurementData {
+        WaveRadonMeasurementData {
+            version: raw.version,
+            humidity: f64::from(raw.humidity) / 2.0,
+            radon_short: parse_radon(raw.radon_short),
+            radon_long: parse_radon(raw.radon_long),
+            temperature: f64::from(raw.temperature) / 100.0,
+        }
+    }
+}
+
+impl From<&WaveMiniRawMeasurementData> for WaveMiniMeasurementData {
+    fn from(raw: &WaveMiniRawMeasurementData) -> WaveMiniMeasurementData {
+        WaveMiniMeasurementData {
+            humidity: f64::from(raw.humidity) / 100.0,
+            // Reported in hundredths of a Kelvin.
+            temperature: f64::from(raw.temperature) / 100.0 - 273.15,
+            voc: f64::from(raw.voc),
+        }
+    }
+}
+
+impl From<&ViewPlusRawMeasurementData> for ViewPlusMeasurementData {
+    fn from(raw: &ViewPlusRawMeasurementData) -> ViewPlusMeasurementData {
+        ViewPlusMeasurementData {
+            version: raw.version,
+            humidity: f64::from(raw.humidity) / 2.0,
+            radon_short: parse_radon(raw.radon_short),
+            radon_long: parse_radon(raw.radon_long),
+            temperature: f64::from(raw.temperature) / 100.0,
+            pressure: f64::from(raw.pressure) / 50.0,
+            co2: f64::from(raw.co2),
+            voc: f64::from(raw.voc),
+            pm1: f64::from(raw.pm1),
+            pm2_5: f64::from(raw.pm2_5),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Address = Address {
+        bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+        kind: 0,
+    };
+
+    #[test]
+    fn queue_entry_round_trips() {
+        let packet = [
+            1, 90, 51, 3, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        let timestamp =
+            OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_456_789).unwrap();
+        let measurement = Measurement {
+            battery: Some(Battery::from_voltage(2.7)),
+            link: LinkDiagnostics {
+                connection_rssi: Some(-70),
+                connect_ms: Some(120),
+                ..LinkDiagnostics::default()
+            },
+            ..Measurement::logged(
+                2930_123456,
+                Model::WavePlus,
+                ADDRESS,
+                Model::WavePlus.decode(&packet).unwrap(),
+                timestamp,
+            )
+            .unwrap()
+        };
+        let entry = measurement.to_queue_entry().unwrap();
+        assert_eq!(Measurement::from_queue_entry(&entry).unwrap(), measurement);
+
+        // Entries queued before the RSSI was renamed.
+        let old = String::from_utf8(entry)
+            .unwrap()
+            .replace("connection_rssi", "rssi");
+        assert_eq!(
+            Measurement::from_queue_entry(old.as_bytes()).unwrap(),
+            measurement
+        );
+    }
+
+    #[test]
+    fn queue_entry_keeps_model() {
+        let packet = [
+            1, 90, 0, 0, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 7, 0, 12, 0,
+            0, 0, 0, 0,
+        ];
+        let measurement = Measurement::logged(
+            2960_123456,
+            Model::ViewPlus,
+            ADDRESS,
+            Model::ViewPlus.decode(&packet).unwrap(),
+            OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap(),
+        )
+        .unwrap();
+        let entry = measurement.to_queue_entry().unwrap();
+        assert_eq!(Measurement::from_queue_entry(&entry).unwrap(), measurement);
+        assert!(Measurement::from_queue_entry(b"waveplus,serial=1 co2=600").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::{anyhow, Result};
+use bincode::Options;
+
+use crate::waveplus::measurement::{
+    MeasurementData, ViewPlusMeasurementData, ViewPlusRawMeasurementData, WaveMiniMeasurementData,
+    WaveMiniRawMeasurementData, WavePlusMeasurementData, WavePlusRawMeasurementData,
+    WaveRadonMeasurementData, WaveRadonRawMeasurementData,
+};
+
+/// The supported Airthings devices, which differ in the GATT characteristic
+/// holding the current values and its layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    WavePlus,
+    WaveRadon,
+    WaveMini,
+    ViewPlus,
+}
+
+impl Model {
+    /// Identify the model from the first four digits of its serial number.
+    /// Unknown prefixes, including the Wave Plus' own 2930, are assumed to
+    /// be a Wave Plus, the originally supported device.
+    pub fn from_serial(serial_number: u32) -> Model {
+        match serial_number / 1_000_000 {
+            2920 => Model::WaveMini,
+            2950 => Model::WaveRadon,
+            2960 => Model::ViewPlus,
+            _ => Model::WavePlus,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::WavePlus => "Wave Plus",
+            Model::WaveRadon => "Wave Radon",
+            Model::WaveMini => "Wave Mini",
+            Model::ViewPlus => "View Plus",
+        }
+    }
+
+    /// Every field the model can report, including radon which is only
+    /// present in some measurements.
+    pub fn fields(&self) -> &'static [&'static str] {
+        match self {
+            Model::WavePlus => &[
+                "humidity",
+                "radon_short",
+                "radon_long",
+                "temperature",
+                "pressure",
+                "co2",
+                "voc",
+                "light",
+                "waves",
+                "battery",
+                "battery_voltage",
+                "battery_low",
+            ],
+            Model::WaveRadon => &["humidity", "radon_short", "radon_long", "temperature"],
+            Model::WaveMini => &["humidity", "temperature", "voc"],
+            Model::ViewPlus => &[
+                "humidity",
+                "radon_short",
+                "radon_long",
+                "temperature",
+                "pressure",
+                "co2",
+                "voc",
+                "pm1",
+                "pm2_5",
+            ],
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        match self {
+            Model::WavePlus | Model::WaveRadon | Model::WaveMini => 20,
+            Model::ViewPlus => 24,
+        }
+    }
+
+    /// Decode the value of the model's current values characteristic.
+    pub fn decode(&self, value: &[u8]) -> Result<MeasurementData> {
+        if value.len() != self.packet_len() {
+            return Err(anyhow!(
+                "Unexpected BLE packet for {}: {:?}",
+                self.name(),
+                value
+            ));
+        }
+        let data = match self {
+            Model::WavePlus => {
+                let raw: WavePlusRawMeasurementData = bincode_options!().deserialize(value)?;
+                MeasurementData::WavePlus(WavePlusMeasurementData::try_from(&raw)?)
+            }
+            Model::WaveRadon => {
+                let raw: WaveRadonRawMeasurementData = bincode_options!().deserialize(value)?;
+                MeasurementData::WaveRadon(WaveRadonMeasurementData::from(&raw))
+            }
+            Model::WaveMini => {
+                let raw: WaveMiniRawMeasurementData = bincode_options!().deserialize(value)?;
+                MeasurementData::WaveMini(WaveMiniMeasurementData::from(&raw))
+            }
+            Model::ViewPlus => {
+                let raw: ViewPlusRawMeasurementData = bincode_options!().deserialize(value)?;
+                MeasurementData::ViewPlus(ViewPlusMeasurementData::from(&raw))
+            }
+        };
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::waveplus::measurement::UnsupportedVersion;
+
+    fn assert_fields(data: MeasurementData, expected: &[(&str, f64)]) {
+        let fields = data.fields();
+        assert_eq!(
+            fields.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            expected.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+        );
+        for ((name, value), (_, expected)) in fields.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-9,
+                "{} is {}, expected {}",
+                name,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn models_from_serial_prefix() {
+        assert_eq!(Model::from_serial(2920_000001), Model::WaveMini);
+        assert_eq!(Model::from_serial(2930_000001), Model::WavePlus);
+        assert_eq!(Model::from_serial(2950_000001), Model::WaveRadon);
+        assert_eq!(Model::from_serial(2960_000001), Model::ViewPlus);
+    }
+
+    #[test]
+    fn decodes_wave_plus() {
+        let packet = [
+            1, 90, 51, 3, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        assert_fields(
+            Model::WavePlus.decode(&packet).unwrap(),
+            &[
+                ("humidity", 45.0),
+                ("radon_short", 100.0),
+                ("radon_long", 110.0),
+                ("temperature", 21.5),
+                ("pressure", 1000.0),
+                ("co2", 600.0),
+                ("voc", 150.0),
+                ("light", 20.0),
+                ("waves", 3.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn decodes_wave_plus_light_and_waves() {
+        // Full light, 255 waves, no radon yet and the sensor's 0xffff for a
+        // long term average it doesn't have.
+        let packet = [
+            1, 60, 255, 255, 0xff, 0xff, 0xff, 0xff, 0xd0, 0x07, 0x20, 0xc4, 0x90, 0x01, 0x2c,
+            0x01, 0, 0, 0, 0,
+        ];
+        assert_fields(
+            Model::WavePlus.decode(&packet).unwrap(),
+            &[
+                ("humidity", 30.0),
+                ("temperature", 20.0),
+                ("pressure", 1004.16),
+                ("co2", 400.0),
+                ("voc", 300.0),
+                ("light", 100.0),
+                ("waves", 255.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_wave_plus_version() {
+        let packet = [
+            2, 90, 51, 3, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 0, 0, 0, 0,
+        ];
+        let err = Model::WavePlus.decode(&packet).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedVersion>(),
+            Some(&UnsupportedVersion {
+                model: Model::WavePlus,
+                version: 2,
+            })
+        );
+        // A truncated packet is a different failure.
+        let err = Model::WavePlus.decode(&packet[..19]).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedVersion>().is_none());
+    }
+
+    #[test]
+    fn decodes_wave_radon() {
+        let packet = [
+            1, 90, 0, 0, 100, 0, 0xff, 0xff, 0x66, 0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        // A long term average the sensor doesn't have yet is left out.
+        assert_fields(
+            Model::WaveRadon.decode(&packet).unwrap(),
+            &[
+                ("humidity", 45.0),
+                ("radon_short", 100.0),
+                ("temperature", 21.5),
+            ],
+        );
+    }
+
+    #[test]
+    fn decodes_wave_mini() {
+        let packet = [
+            0, 0, 0x77, 0x74, 0, 0, 0xc6, 0x11, 150, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_fields(
+            Model::WaveMini.decode(&packet).unwrap(),
+            &[("humidity", 45.5), ("temperature", 25.0), ("voc", 150.0)],
+        );
+    }
+
+    #[test]
+    fn decodes_view_plus() {
+        let packet = [
+            1, 90, 0, 0, 100, 0, 110, 0, 0x66, 0x08, 0x50, 0xc3, 0x58, 0x02, 150, 0, 5, 0, 8, 0, 0,
+            0, 0, 0,
+        ];
+        assert_fields(
+            Model::ViewPlus.decode(&packet).unwrap(),
+            &[
+                ("humidity", 45.0),
+                ("radon_short", 100.0),
+                ("radon_long", 110.0),
+                ("temperature", 21.5),
+                ("pressure", 1000.0),
+                ("co2", 600.0),
+                ("voc", 150.0),
+                ("pm1", 5.0),
+                ("pm2_5", 8.0),
+            ],
+        );
+        assert!(Model::ViewPlus.decode(&packet[..20]).is_err());
+    }
+}
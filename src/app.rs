@@ -1,121 +1,469 @@
 use anyhow::Result;
-use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspNvs, NvsCustom, NvsDefault};
 use esp_idf_svc::wifi::EspWifi;
 use log::*;
-use time::PrimitiveDateTime;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 mod http;
-mod state;
+mod mqtt;
+mod ota;
+mod platform;
+mod server;
+mod tls;
 
+use waveplus_core::app::{
+    effects, fingerprint, influx, metrics, payload, queue, radon, retry, signing, sink, state,
+    topics, version,
+};
+
+use crate::app::effects::{Clock, Network, Sensor, Sink, Store};
+use crate::app::http::Endpoint;
+use crate::app::metrics::Metrics;
+use crate::app::mqtt::MqttPublisher;
+use crate::app::platform::{
+    HttpSink, NvsQueueStorage, NvsStore, SystemClock, WavePlusSensor, WifiNetwork,
+};
+use crate::app::queue::{MeasurementQueue, QueueStorage};
+use crate::app::retry::{RetryPolicy, SendError};
+use crate::app::sink::{confirming_sink, LogSink};
 use crate::app::state::*;
 use crate::rgbled::{RGB8, WS2812RMT};
-use crate::utils::time::get_datetime;
-use crate::waveplus::{get_waveplus, read_waveplus};
-use crate::wifi::wait_for_connected;
+use crate::waveplus::measurement::UnsupportedVersion;
+use crate::waveplus::{self, DiscoveredDevice, ScanSettings};
 
+pub use crate::app::influx::InfluxSettings;
+pub use crate::app::mqtt::MqttSettings;
+pub use crate::app::payload::PayloadFormat;
+pub use crate::app::radon::RadonSchedule;
+pub use crate::app::sink::{parse_sinks, SinkKind};
 pub use crate::app::state::Status;
+pub use crate::app::tls::Tls;
+pub use waveplus_core::app::auth::{parse_headers, Auth};
 
-fn should_include_radon(last: Option<PrimitiveDateTime>, current: PrimitiveDateTime) -> bool {
-    warn!("last run {:?}, current run {:?}", last, current);
-    if let Some(last) = last {
-        last.hour() < current.hour()
-    } else {
-        true
+/// Runtime settings for the measurement loop.
+pub struct Settings<'a> {
+    pub serials: Vec<u32>,
+    pub server: &'a str,
+    pub format: PayloadFormat,
+    pub influx: InfluxSettings<'a>,
+    /// Where each round of measurements is sent.
+    pub sinks: Vec<SinkKind>,
+    pub read_interval: u16,
+    pub radon: RadonSchedule,
+    pub scan: ScanSettings,
+    /// Read each device at most every this many minutes rather than every
+    /// cycle, 0 to read on every cycle.
+    pub min_read_interval: u32,
+    pub queue_capacity: u16,
+    pub batch_size: u16,
+    pub batch_max_bytes: u32,
+    pub mqtt: Option<MqttSettings<'a>>,
+    pub auth: Auth,
+    pub headers: Vec<(String, String)>,
+    pub signing_secret: &'a str,
+    pub tls: Tls,
+    pub http_retries: u32,
+    pub http_retry_delay: u32,
+    pub http_port: u16,
+    pub ota_url: &'a str,
+    pub ota_interval: u32,
+}
+
+/// The upload URL and headers for the configured payload format, with the
+/// configured credentials taking precedence over the InfluxDB token.
+fn endpoint(settings: &Settings) -> Endpoint {
+    let mut headers = vec![(
+        "content-type".to_string(),
+        settings.format.content_type().to_string(),
+    )];
+    let url = match settings.format {
+        PayloadFormat::Json => settings.server.to_string(),
+        PayloadFormat::Influx => {
+            if settings.auth == Auth::None && !settings.influx.token.is_empty() {
+                headers.push((
+                    "authorization".to_string(),
+                    format!("Token {}", settings.influx.token),
+                ));
+            }
+            influx::write_url(settings.server, &settings.influx)
+        }
+    };
+    if let Some(authorization) = settings.auth.header() {
+        headers.push(("authorization".to_string(), authorization));
+    }
+    headers.extend(settings.headers.iter().cloned());
+    if settings.tls.fingerprint.is_some() && !url.starts_with("https://") {
+        warn!("Ignoring the pinned certificate for {}", url);
+    }
+    Endpoint {
+        url,
+        headers,
+        signing_secret: (!settings.signing_secret.is_empty())
+            .then(|| settings.signing_secret.as_bytes().to_vec()),
+        tls: settings.tls,
     }
 }
 
-pub fn run(
-    wifi: &mut EspWifi,
-    led: &mut WS2812RMT,
-    serial: u32,
-    server: &str,
-    read_interval: u16,
-) -> Result<()> {
-    let mut state: State = State::default();
-    loop {
-        led.set_pixel(RGB8::from(state.status))?;
-        info!("Current state: {:?}", state);
-        state = match state.mode {
-            ExecutionMode::Initialize | ExecutionMode::Reinitialize => {
-                let waveplus = get_waveplus(&serial).expect("Unable to get waveplus bt device");
-                let newstate = state
-                    .with_mode(ExecutionMode::CollectMeasurement)
-                    .with_waveplus(waveplus);
-                match state.mode {
-                    ExecutionMode::Reinitialize => newstate.ble_disconnected(),
-                    _ => newstate,
+/// The side effects available to the measurement loop.
+struct Effects<'s, S, N, Q, T, C>
+where
+    Q: QueueStorage,
+{
+    sensor: S,
+    network: N,
+    queue: MeasurementQueue<Q>,
+    sinks: Vec<Box<dyn Sink + 's>>,
+    store: T,
+    clock: C,
+}
+
+impl<S, N, Q, T, C> Effects<'_, S, N, Q, T, C>
+where
+    S: Sensor,
+    N: Network,
+    Q: QueueStorage,
+    T: Store,
+    C: Clock,
+{
+    fn perform(&mut self, action: Action, state: &State, settings: &Settings) -> Result<Event> {
+        let event = match action {
+            Action::ScanWavePlus => {
+                let serials: Vec<u32> = state
+                    .devices
+                    .iter()
+                    .filter(|device| device.address.is_none())
+                    .map(|device| device.serial)
+                    .collect();
+                match self.sensor.scan(&serials) {
+                    Ok(found) => Event::WavePlusFound(found),
+                    Err(err) => {
+                        error!("Failed to scan for Wave Plus devices: {:?}", err);
+                        Event::ScanFailed
+                    }
                 }
             }
-            ExecutionMode::WifiDisconnect => {
-                warn!(
-                    "Wifi connected: {:?}, up: {:?}",
-                    wifi.is_connected()?,
-                    wifi.is_up()?
-                );
-
-                if wifi.is_connected()? {
-                    // If we're here and the wifi device thinks it's
-                    // connected, trigger a disconnect event and wait
-                    // for re-connect.
-                    warn!("Disconnecting wifi for connection retry");
-                    if let Err(err) = wifi.disconnect() {
-                        error!("Error calling wifi.disconnect after http failure {:?}", err);
+            Action::SaveAddresses(found) => {
+                for (serial, address) in found.iter() {
+                    if let Err(err) = self.store.save_address(*serial, address) {
+                        error!("Failed to cache address of {:?}: {:?}", serial, err);
                     }
                 }
-
-                FreeRtos::delay_ms(250);
-                state
-                    .with_mode(ExecutionMode::WifiReconnect)
-                    .wifi_disconnected()
+                Event::AddressesSaved
             }
-            ExecutionMode::WifiReconnect => {
-                warn!(
-                    "Wifi connected: {:?}, up: {:?}",
-                    wifi.is_connected()?,
-                    wifi.is_up()?
-                );
-
-                wait_for_connected(wifi)?;
-
-                state.with_mode(ExecutionMode::CollectMeasurement)
+            Action::ForgetAddresses(failed) => {
+                for serial in failed.iter() {
+                    if let Err(err) = self.store.remove_address(*serial) {
+                        error!("Failed to forget address of {:?}: {:?}", serial, err);
+                    }
+                }
+                Event::AddressesForgotten
             }
-            ExecutionMode::CollectMeasurement => {
-                let current = get_datetime()?;
-                let include_radon =
-                    should_include_radon(state.last_run, current) || state.force_radon_measurement;
-
-                if let Some(waveplus) = state.waveplus {
-                    warn!("Include radon measurement? {:?}", include_radon);
-                    match read_waveplus(serial, &waveplus, include_radon) {
-                        Ok(measurement) => state
-                            .with_mode(ExecutionMode::SendMeasurement)
-                            .with_measurement(measurement),
+            Action::ReadWavePlus { serials, radon } => {
+                warn!("Include radon measurement of {:?}", radon);
+                let mut measurements = Vec::new();
+                let mut failed = Vec::new();
+                let mut unsupported = Vec::new();
+                for device in state.devices.iter() {
+                    if !serials.contains(&device.serial) {
+                        continue;
+                    }
+                    let Some(address) = device.address else {
+                        continue;
+                    };
+                    let include_radon = radon.contains(&device.serial);
+                    match self.sensor.read(device.serial, &address, include_radon) {
+                        Ok(measurement) => measurements.push(measurement),
+                        Err(err) if err.downcast_ref::<UnsupportedVersion>().is_some() => {
+                            error!("Failed to decode data from {}: {}", address, err);
+                            unsupported.push(device.serial);
+                        }
                         Err(err) => {
-                            error!("Failed to retrieve data from {:?}: {:?}", waveplus, err);
-                            state.with_mode(ExecutionMode::Reinitialize)
+                            error!("Failed to retrieve data from {}: {:?}", address, err);
+                            failed.push(device.serial);
                         }
                     }
-                } else {
-                    state.with_mode(ExecutionMode::Reinitialize)
+                }
+                Event::MeasurementsRead {
+                    measurements,
+                    failed,
+                    unsupported,
                 }
             }
-            ExecutionMode::SendMeasurement => {
-                let current = get_datetime()?;
-                let newstate = if http::send(&state, server).err().is_some() {
-                    state
-                        .with_mode(ExecutionMode::WifiDisconnect)
-                        .force_radon_measurement(state.measurement_has_radon())
-                        .http_error()
-                } else {
-                    state.with_mode(ExecutionMode::Wait)
+            Action::SendMeasurement => {
+                let mut sent = Vec::new();
+                let mut failed = Vec::new();
+                let mut upload_error = None;
+                for sink in self.sinks.iter_mut() {
+                    // Behind a backlog the upload was queued, to be sent in
+                    // order when the queue drains.
+                    if state.queued > 0 && sink.kind() == SinkKind::Http {
+                        continue;
+                    }
+                    match sink.send(state) {
+                        Ok(()) => sent.push(sink.kind()),
+                        Err(err) => {
+                            error!("Failed to send measurement to {}: {}", sink.kind(), err);
+                            failed.push(sink.kind());
+                            if sink.kind() == SinkKind::Http {
+                                upload_error = err.downcast::<SendError>().ok();
+                            }
+                        }
+                    }
+                }
+                Event::MeasurementSent {
+                    sent,
+                    failed,
+                    link_lost: upload_error
+                        .as_ref()
+                        .is_some_and(|err| self.connectivity_lost(err)),
+                    rejected: upload_error.is_some_and(|err| err.is_rejection()),
+                }
+            }
+            Action::EnqueueMeasurements(measurements) => {
+                for measurement in measurements.iter() {
+                    let queued = measurement
+                        .to_queue_entry()
+                        .and_then(|entry| self.queue.push(&entry));
+                    if let Err(err) = queued {
+                        error!("Failed to queue measurement {:?}: {:?}", measurement, err);
+                    }
+                }
+                info!("{} measurements queued", self.queue.len());
+                Event::MeasurementsQueued(self.queue.len())
+            }
+            Action::SendQueued => {
+                let entries = match self
+                    .queue
+                    .peek_batch(usize::from(settings.batch_size.max(1)))
+                {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        error!("Failed to read queued measurements: {:?}", err);
+                        return Ok(Event::QueueFailed(self.queue.len()));
+                    }
                 };
-                newstate.with_last_run(current)
+                if entries.is_empty() {
+                    return Ok(Event::QueuedSent(0));
+                }
+                let (payload, count) = settings.format.encode_batch(
+                    state,
+                    &entries,
+                    settings.batch_max_bytes as usize,
+                );
+                info!("Sending {} queued measurements", count);
+                match self.network.send(&payload) {
+                    // A batch that can't be removed is sent again next cycle.
+                    Ok(()) => match self.queue.pop_many(count) {
+                        Ok(()) => Event::QueuedSent(self.queue.len()),
+                        Err(err) => {
+                            error!("Failed to remove sent measurements: {:?}", err);
+                            Event::QueueFailed(self.queue.len())
+                        }
+                    },
+                    Err(err) if err.is_rejection() => {
+                        error!("Dropping {} queued measurements: {}", count, err);
+                        match self.queue.pop_many(count) {
+                            Ok(()) => Event::QueuedDropped(self.queue.len()),
+                            Err(err) => {
+                                error!("Failed to remove rejected measurements: {:?}", err);
+                                Event::QueueFailed(self.queue.len())
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("Failed to send queued measurements: {}", err);
+                        if self.connectivity_lost(&err) {
+                            Event::QueuedSendFailedLinkDown
+                        } else {
+                            Event::QueuedSendFailedLinkUp
+                        }
+                    }
+                }
+            }
+            Action::SaveRadonReport(serials, at) => {
+                for serial in serials.iter() {
+                    if let Err(err) = self.store.save_radon_report(*serial, at) {
+                        error!(
+                            "Failed to save radon report time of {:?}: {:?}",
+                            serial, err
+                        );
+                    }
+                }
+                Event::RadonReportSaved
+            }
+            Action::DisconnectWifi => {
+                // Waiting for the link to come back is worth trying anyway.
+                if let Err(err) = self.network.disconnect() {
+                    error!("Failed to disconnect wifi: {:?}", err);
+                }
+                Event::WifiDisconnected
+            }
+            Action::WaitForWifi => match self.network.wait_for_connected() {
+                Ok(()) => Event::WifiConnected,
+                Err(err) => {
+                    error!("Failed to reconnect wifi: {:?}", err);
+                    Event::WifiReconnectFailed
+                }
+            },
+            Action::Backoff(ms) => {
+                warn!("Backing off for {} ms", ms);
+                self.clock.delay_ms(ms);
+                Event::BackoffElapsed
+            }
+            Action::Wait => {
+                self.clock
+                    .delay_ms(u32::from(settings.read_interval) * 1000);
+                Event::WaitElapsed
+            }
+        };
+        Ok(event)
+    }
+
+    /// Whether a failed upload is down to the Wi-Fi link rather than the
+    /// server, so that reconnecting could help.
+    fn connectivity_lost(&self, err: &SendError) -> bool {
+        matches!(err, SendError::Transport(_)) && !self.network.is_connected().unwrap_or(false)
+    }
+}
+
+/// The colour of the status LED, kept out of the state machine so that it
+/// doesn't depend on the LED driver.
+pub fn color(status: Status) -> RGB8 {
+    match status {
+        Status::Initializing => RGB8::new(10, 10, 0),
+        Status::Ready => RGB8::new(0, 10, 0),
+        Status::Collecting => RGB8::new(0, 0, 10),
+        Status::Sending => RGB8::new(0, 10, 10),
+        Status::Error => RGB8::new(10, 0, 0),
+        Status::Recovering => RGB8::new(10, 0, 10),
+    }
+}
+
+pub fn run(
+    wifi: &mut EspWifi,
+    led: &mut WS2812RMT,
+    queue_nvs: EspNvs<NvsCustom>,
+    state_nvs: EspNvs<NvsDefault>,
+    settings: &Settings,
+    discovered: Vec<DiscoveredDevice>,
+) -> Result<()> {
+    let metrics = Arc::new(Mutex::new(Metrics::new(Instant::now())));
+    let ota = if settings.ota_url.is_empty() {
+        None
+    } else {
+        Some(ota::spawn(
+            settings.ota_url.to_string(),
+            Duration::from_secs(u64::from(settings.ota_interval)),
+            settings.tls.unpinned(),
+        )?)
+    };
+    let discovered = Arc::new(Mutex::new(discovered));
+    let (discover, discover_requests) = sync_channel(1);
+    let _server = server::start(
+        settings.http_port,
+        metrics.clone(),
+        ota,
+        discovered.clone(),
+        discover,
+    )?;
+    let firmware_valid = Arc::new(AtomicBool::new(false));
+    if ota::is_pending_verify() {
+        ota::spawn_deadline(firmware_valid.clone(), ota::CONFIRM_DEADLINE)?;
+    }
+
+    let queue = MeasurementQueue::new(
+        NvsQueueStorage { nvs: queue_nvs },
+        u32::from(settings.queue_capacity),
+    )?;
+    let retry = RetryPolicy {
+        retries: settings.http_retries,
+        base_delay: Duration::from_millis(u64::from(settings.http_retry_delay)),
+        max_delay: Duration::from_secs(60),
+    };
+    let mut sinks: Vec<Box<dyn Sink + '_>> = Vec::new();
+    for kind in settings.sinks.iter() {
+        match kind {
+            SinkKind::Http => sinks.push(Box::new(HttpSink {
+                endpoint: endpoint(settings),
+                retry,
+                format: settings.format,
+            })),
+            SinkKind::Mqtt => match &settings.mqtt {
+                Some(mqtt) => sinks.push(Box::new(MqttPublisher::new(mqtt)?)),
+                None => info!("No mqtt_url configured, skipping the mqtt sink"),
+            },
+            SinkKind::Log => sinks.push(Box::new(LogSink)),
+        }
+    }
+    let kinds: Vec<SinkKind> = sinks.iter().map(|sink| sink.kind()).collect();
+    let confirming = confirming_sink(&kinds);
+    let mut effects = Effects {
+        sensor: WavePlusSensor {
+            scan: settings.scan,
+        },
+        network: WifiNetwork {
+            wifi,
+            endpoint: endpoint(settings),
+            retry,
+        },
+        queue,
+        sinks,
+        store: NvsStore { nvs: state_nvs },
+        clock: SystemClock,
+    };
+
+    let state = State::new(&settings.serials, settings.radon)
+        .with_firmware(ota::VERSION)
+        .with_queued(effects.queue.len())
+        .with_radon_reports(&effects.store.load_radon_reports(&settings.serials)?)
+        .with_min_read_interval(
+            (settings.min_read_interval > 0)
+                .then(|| time::Duration::minutes(i64::from(settings.min_read_interval))),
+        )
+        .with_cached_addresses(&effects.store.load_addresses(&settings.serials)?);
+    let (mut state, start) = state.start(effects.clock.now()?);
+    let mut actions: VecDeque<Action> = start.into();
+    while let Some(action) = actions.pop_front() {
+        // Requested discoveries run between actions, while BLE is idle.
+        if discover_requests.try_recv().is_ok() {
+            match waveplus::discover(&settings.scan) {
+                Ok(found) => {
+                    if let Ok(mut discovered) = discovered.lock() {
+                        *discovered = found;
+                    }
+                }
+                Err(err) => error!("Failed to discover Airthings devices: {:?}", err),
             }
-            ExecutionMode::Wait => {
-                let delay_ms = u32::from(read_interval) * 1000;
-                FreeRtos::delay_ms(delay_ms);
-                state.with_mode(ExecutionMode::CollectMeasurement)
+        }
+        led.set_pixel(color(state.status))?;
+        info!("Current state: {:?}, performing {:?}", state, action);
+        let event = effects.perform(action, &state, settings)?;
+        let confirmed = match &event {
+            Event::MeasurementSent { sent, .. } => {
+                confirming.map_or(!sent.is_empty(), |kind| sent.contains(&kind))
             }
+            Event::QueuedSent(_) => confirming == Some(SinkKind::Http),
+            _ => false,
         };
+        if confirmed && !firmware_valid.load(Ordering::SeqCst) {
+            // Until a measurement has been sent a reboot rolls back to the
+            // previous firmware.
+            info!("Marking firmware {} valid", ota::VERSION);
+            if let Err(err) = ota::mark_running_slot_valid() {
+                error!("Failed to mark firmware valid: {:?}", err);
+            }
+            firmware_valid.store(true, Ordering::SeqCst);
+        }
+        let (newstate, next) = state.step(event, effects.clock.now()?);
+        state = newstate;
+        actions.extend(next);
+        if let Ok(mut metrics) = metrics.lock() {
+            metrics.update(&state, Instant::now());
+        }
     }
+    Ok(())
 }
@@ -1,36 +1,93 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use embedded_svc::http::{client::Client, Method};
-use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::io::EspIOError;
+use std::fmt;
+use time::OffsetDateTime;
 
-use crate::app::state::State;
+use crate::app::retry::{parse_retry_after, SendError};
+use crate::app::signing;
+use crate::app::tls::Tls;
 
-pub fn send(state: &State, url: impl AsRef<str>) -> Result<()> {
+/// Where and how measurements are uploaded.
+pub struct Endpoint {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// Secret to sign each request body with, see [`signing`].
+    pub signing_secret: Option<Vec<u8>>,
+    pub tls: Tls,
+}
+
+/// Only the header names are shown, as the values may hold credentials.
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.headers.iter().map(|(name, _)| name.as_str()).collect();
+        f.debug_struct("Endpoint")
+            .field("url", &self.url)
+            .field("headers", &names)
+            .field("signed", &self.signing_secret.is_some())
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+pub fn client(tls: &Tls) -> Result<Client<EspHttpConnection>> {
     // 1. Create a new EspHttpClient. (Check documentation)
     // ANCHOR: connection
-    let connection = EspHttpConnection::new(&Configuration {
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-        timeout: Some(std::time::Duration::from_secs(30)),
-        ..Default::default()
-    })?;
+    let connection = EspHttpConnection::new(&tls.configuration())?;
     // ANCHOR_END: connection
-    let mut client = Client::wrap(connection);
+    Ok(Client::wrap(connection))
+}
+
+fn transport(err: EspIOError) -> SendError {
+    SendError::Transport(err.into())
+}
+
+pub fn send(body: &str, endpoint: &Endpoint) -> Result<(), SendError> {
+    let mut client = client(&endpoint.tls)?;
+
+    // Each attempt is signed afresh so that retries are not taken for
+    // replays.
+    let mut signature = Vec::new();
+    if let Some(secret) = &endpoint.signing_secret {
+        let mut random = [0u8; 16];
+        unsafe { esp_idf_svc::sys::esp_fill_random(random.as_mut_ptr().cast(), random.len()) };
+        let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+        signature.extend(signing::headers(
+            secret,
+            timestamp,
+            &signing::nonce(&random),
+            body,
+        ));
+    }
 
     // 2. Open a GET request to `url`
-    let headers = [("content-type", "application/json")];
-    let mut request = client.request(Method::Post, url.as_ref(), &headers)?;
-    let json = serde_json::to_string(state)?;
-    request.write(json.as_bytes())?;
+    let headers: Vec<(&str, &str)> = endpoint
+        .headers
+        .iter()
+        .chain(signature.iter())
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    let mut request = client
+        .request(Method::Post, &endpoint.url, &headers)
+        .map_err(transport)?;
+    request.write(body.as_bytes()).map_err(transport)?;
 
     // 3. Submit write request and check the status code of the response.
     // Successful http status codes are in the 200..=299 range.
-    let response = request.submit()?;
+    let response = request.submit().map_err(transport)?;
     let status = response.status();
 
     println!("Response code: {}\n", status);
 
     match status {
         200..=299 => Ok(()),
-        _ => bail!("Unexpected response code: {}", status),
+        429 => Err(SendError::TooManyRequests(
+            response
+                .header("retry-after")
+                .and_then(|value| parse_retry_after(value, OffsetDateTime::now_utc())),
+        )),
+        400..=499 => Err(SendError::Client(status)),
+        _ => Err(SendError::Server(status)),
     }
 }
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use log::*;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::app::effects::Sink;
+use crate::app::sink::SinkKind;
+use crate::app::state::State;
+use crate::app::topics::{self, Topics};
+use crate::waveplus::measurement::Measurement;
+use crate::waveplus::model::Model;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+pub struct MqttSettings<'a> {
+    pub url: &'a str,
+    pub client_id: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub prefix: &'a str,
+    pub discovery_prefix: &'a str,
+}
+
+pub struct MqttPublisher<'a> {
+    client: EspMqttClient<'static>,
+    topics: Topics<'a>,
+    online: Arc<AtomicBool>,
+    announced: HashSet<u32>,
+}
+
+impl<'a> MqttPublisher<'a> {
+    pub fn new(settings: &MqttSettings<'a>) -> Result<Self> {
+        let topics = Topics {
+            prefix: settings.prefix,
+            discovery_prefix: settings.discovery_prefix,
+        };
+        let availability = topics.availability();
+        let conf = MqttClientConfiguration {
+            client_id: Some(settings.client_id),
+            username: (!settings.username.is_empty()).then_some(settings.username),
+            password: (!settings.password.is_empty()).then_some(settings.password),
+            lwt: Some(LwtConfiguration {
+                topic: &availability,
+                payload: OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        };
+
+        let online = Arc::new(AtomicBool::new(false));
+        let disconnected = online.clone();
+        let client = EspMqttClient::new_cb(settings.url, &conf, move |event| {
+            match event.payload() {
+                EventPayload::Connected(_) => info!("MQTT connected"),
+                EventPayload::Disconnected => {
+                    warn!("MQTT disconnected");
+                    // Availability and discovery are re-published on the
+                    // next measurement after the broker has seen the LWT.
+                    disconnected.store(false, Ordering::SeqCst);
+                }
+                EventPayload::Error(err) => error!("MQTT error {:?}", err),
+                _ => {}
+            }
+        })?;
+
+        Ok(MqttPublisher {
+            client,
+            topics,
+            online,
+            announced: HashSet::new(),
+        })
+    }
+
+    fn enqueue(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<()> {
+        self.client
+            .enqueue(topic, QoS::AtLeastOnce, retain, payload)?;
+        Ok(())
+    }
+
+    fn announce(&mut self, serial: u32, model: Model) -> Result<()> {
+        for field in topics::fields(model) {
+            let topic = self.topics.discovery(serial, field);
+            let config = self
+                .topics
+                .discovery_config(serial, model, field)
+                .to_string();
+            self.enqueue(&topic, true, config.as_bytes())?;
+        }
+        self.announced.insert(serial);
+        Ok(())
+    }
+
+    fn publish(&mut self, measurements: &[Measurement]) -> Result<()> {
+        if !self.online.swap(true, Ordering::SeqCst) {
+            self.announced.clear();
+            let topic = self.topics.availability();
+            self.enqueue(&topic, true, ONLINE.as_bytes())?;
+        }
+
+        for measurement in measurements {
+            let serial = measurement.metadata.serial_number;
+            if !self.announced.contains(&serial) {
+                self.announce(serial, measurement.metadata.model)?;
+            }
+            for (field, value) in measurement.fields() {
+                let topic = self.topics.state(serial, field);
+                self.enqueue(&topic, false, value.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for MqttPublisher<'_> {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Mqtt
+    }
+
+    fn send(&mut self, state: &State) -> Result<()> {
+        self.publish(&state.measurements)
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{bail, Result};
+use embedded_svc::io::Read;
+use esp_idf_svc::ota::EspOta;
+use log::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::app::http;
+use crate::app::tls::Tls;
+use crate::app::version::is_newer;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The size of each OTA slot in `partitions.csv`. An image that doesn't fit
+/// is refused before it overwrites the inactive slot.
+pub const SLOT_SIZE: usize = 0x180000;
+
+/// How long a new firmware has to confirm itself by sending a measurement
+/// before it reboots, letting the bootloader roll back.
+pub const CONFIRM_DEADLINE: Duration = Duration::from_secs(15 * 60);
+
+/// The JSON document at the configured update URL describing the latest
+/// firmware.
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    pub sha256: String,
+    /// The size of the image in bytes, to refuse one that can't fit before
+    /// downloading it.
+    #[serde(default)]
+    pub size: Option<usize>,
+}
+
+/// Whether the running firmware was just installed and has yet to be
+/// marked valid.
+pub fn is_pending_verify() -> bool {
+    use esp_idf_svc::sys::*;
+
+    let mut state = esp_ota_img_states_t_ESP_OTA_IMG_UNDEFINED;
+    let result =
+        unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+    result == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+/// The version of the firmware the bootloader last rolled back from, if
+/// its image is still in the inactive slot. The version is the one
+/// esp-idf-sys embeds in the app description, the crate version.
+pub fn rejected_version() -> Option<String> {
+    use esp_idf_svc::sys::*;
+
+    let partition = unsafe { esp_ota_get_last_invalid_partition() };
+    if partition.is_null() {
+        return None;
+    }
+    let mut description: esp_app_desc_t = unsafe { std::mem::zeroed() };
+    if unsafe { esp_ota_get_partition_description(partition, &mut description) } != ESP_OK {
+        return None;
+    }
+    let version = unsafe { CStr::from_ptr(description.version.as_ptr()) };
+    Some(version.to_string_lossy().into_owned())
+}
+
+/// Reboot unless `confirmed` is set within `deadline`. Without it a new
+/// firmware that hangs before sending a measurement would never reboot,
+/// and so never be rolled back.
+pub fn spawn_deadline(confirmed: Arc<AtomicBool>, deadline: Duration) -> Result<()> {
+    std::thread::Builder::new()
+        .name("ota-deadline".to_string())
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            std::thread::sleep(deadline);
+            if !confirmed.load(Ordering::SeqCst) {
+                error!(
+                    "Firmware {} not confirmed within {:?}, rebooting to roll back",
+                    VERSION, deadline
+                );
+                esp_idf_svc::hal::reset::restart();
+            }
+        })?;
+    Ok(())
+}
+
+/// Confirm that the running firmware works, cancelling the rollback to the
+/// previous slot that would otherwise happen on the next reboot.
+pub fn mark_running_slot_valid() -> Result<()> {
+    let mut ota = EspOta::new()?;
+    ota.mark_running_slot_valid()?;
+    Ok(())
+}
+
+fn fetch_manifest(url: &str, tls: &Tls) -> Result<Manifest> {
+    let mut client = http::client(tls)?;
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    if !(200..=299).contains(&status) {
+        bail!("Unexpected response code fetching manifest: {}", status);
+    }
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = response.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..len]);
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Download the image described by `manifest` into the next OTA slot,
+/// activating it only if its SHA-256 matches.
+fn install(manifest: &Manifest, tls: &Tls) -> Result<()> {
+    let mut client = http::client(tls)?;
+    if let Some(size) = manifest.size.filter(|size| *size > SLOT_SIZE) {
+        bail!(
+            "Firmware of {} bytes doesn't fit the {} byte OTA slot",
+            size,
+            SLOT_SIZE
+        );
+    }
+    let mut response = client.get(&manifest.url)?.submit()?;
+    let status = response.status();
+    if !(200..=299).contains(&status) {
+        bail!("Unexpected response code fetching firmware: {}", status);
+    }
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 1024];
+    let mut total = 0;
+    loop {
+        let len = match response.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) => {
+                update.abort()?;
+                bail!("Failed to download firmware: {:?}", err);
+            }
+        };
+        if len == 0 {
+            break;
+        }
+        if total + len > SLOT_SIZE {
+            update.abort()?;
+            bail!("Firmware doesn't fit the {} byte OTA slot", SLOT_SIZE);
+        }
+        hasher.update(&buf[..len]);
+        update.write(&buf[..len])?;
+        total += len;
+    }
+
+    let digest: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if !digest.eq_ignore_ascii_case(manifest.sha256.trim()) {
+        update.abort()?;
+        bail!(
+            "Firmware checksum mismatch, expected {} got {}",
+            manifest.sha256,
+            digest
+        );
+    }
+
+    info!(
+        "Downloaded {} bytes of firmware {}",
+        total, manifest.version
+    );
+    update.complete()?;
+    Ok(())
+}
+
+/// Install and reboot into the firmware at `url` if it is newer than the
+/// running one and wasn't rolled back from before.
+pub fn check(url: &str, tls: &Tls) -> Result<()> {
+    let manifest = fetch_manifest(url, tls)?;
+    if !is_newer(&manifest.version, VERSION) {
+        info!(
+            "Firmware {} is up to date (latest {})",
+            VERSION, manifest.version
+        );
+        return Ok(());
+    }
+    // Reinstalling it would only be rolled back again, until the manifest
+    // moves on to another version.
+    if rejected_version().is_some_and(|rejected| {
+        rejected.trim_start_matches('v') == manifest.version.trim_start_matches('v')
+    }) {
+        warn!(
+            "Skipping firmware {}, which was rolled back before",
+            manifest.version
+        );
+        return Ok(());
+    }
+
+    info!("Updating firmware from {} to {}", VERSION, manifest.version);
+    install(&manifest, tls)?;
+    info!("Firmware updated, rebooting");
+    esp_idf_svc::hal::reset::restart();
+}
+
+/// Check `url` for updates every `interval`, or when triggered through the
+/// returned sender.
+pub fn spawn(url: String, interval: Duration, tls: Tls) -> Result<SyncSender<()>> {
+    let (trigger, triggered) = mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name("ota".to_string())
+        .stack_size(12 * 1024)
+        .spawn(move || loop {
+            match triggered.recv_timeout(interval) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {
+                    if let Err(err) = check(&url, &tls) {
+                        error!("Firmware update failed: {:?}", err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        })?;
+    Ok(trigger)
+}
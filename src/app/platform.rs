@@ -0,0 +1,237 @@
+use anyhow::Result;
+use esp_idf_svc::hal::delay::FreeRtos;
+use esp_idf_svc::nvs::{EspNvs, NvsCustom, NvsDefault};
+use esp_idf_svc::wifi::EspWifi;
+use log::*;
+use time::OffsetDateTime;
+
+use crate::app::effects::{Clock, Network, Sensor, Sink, Store};
+use crate::app::http::{self, Endpoint};
+use crate::app::payload::PayloadFormat;
+use crate::app::queue::QueueStorage;
+use crate::app::retry::{RetryPolicy, SendError};
+use crate::app::sink::SinkKind;
+use crate::app::state::State;
+use crate::waveplus::address::Address;
+use crate::waveplus::measurement::Measurement;
+use crate::waveplus::{get_waveplus, read_waveplus, ScanSettings};
+use crate::wifi::wait_for_connected;
+
+pub struct WavePlusSensor {
+    pub scan: ScanSettings,
+}
+
+impl Sensor for WavePlusSensor {
+    fn scan(&mut self, serials: &[u32]) -> Result<Vec<(u32, Address)>> {
+        get_waveplus(serials, &self.scan)
+    }
+
+    fn read(&mut self, serial: u32, address: &Address, include_radon: bool) -> Result<Measurement> {
+        read_waveplus(serial, address, include_radon)
+    }
+}
+
+pub struct WifiNetwork<'a, 'd> {
+    pub wifi: &'a mut EspWifi<'d>,
+    pub endpoint: Endpoint,
+    pub retry: RetryPolicy,
+}
+
+/// Post `body` to `endpoint`, retrying as `retry` allows.
+fn upload(body: &str, endpoint: &Endpoint, retry: &RetryPolicy) -> Result<(), SendError> {
+    let mut attempt = 0;
+    loop {
+        let err = match http::send(body, endpoint) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let jitter = unsafe { esp_idf_svc::sys::esp_random() };
+        let Some(delay) = retry.delay(attempt, &err, jitter) else {
+            return Err(err);
+        };
+        warn!("Upload failed: {}, retrying in {:?}", err, delay);
+        FreeRtos::delay_ms(u32::try_from(delay.as_millis()).unwrap_or(u32::MAX));
+        attempt += 1;
+    }
+}
+
+impl Network for WifiNetwork<'_, '_> {
+    fn send(&mut self, body: &str) -> Result<(), SendError> {
+        upload(body, &self.endpoint, &self.retry)
+    }
+
+    fn is_connected(&self) -> Result<bool> {
+        Ok(self.wifi.is_connected()? && self.wifi.is_up()?)
+    }
+
+    fn disconnect(&mut self) -> Result<()> {
+        warn!(
+            "Wifi connected: {:?}, up: {:?}",
+            self.wifi.is_connected()?,
+            self.wifi.is_up()?
+        );
+
+        if self.wifi.is_connected()? {
+            // If we're here and the wifi device thinks it's
+            // connected, trigger a disconnect event and wait
+            // for re-connect.
+            warn!("Disconnecting wifi for connection retry");
+            if let Err(err) = self.wifi.disconnect() {
+                error!("Error calling wifi.disconnect after http failure {:?}", err);
+            }
+        }
+
+        FreeRtos::delay_ms(250);
+        Ok(())
+    }
+
+    fn wait_for_connected(&mut self) -> Result<()> {
+        warn!(
+            "Wifi connected: {:?}, up: {:?}",
+            self.wifi.is_connected()?,
+            self.wifi.is_up()?
+        );
+
+        wait_for_connected(self.wifi)
+    }
+}
+
+/// Uploads each round of measurements to the configured server.
+pub struct HttpSink {
+    pub endpoint: Endpoint,
+    pub retry: RetryPolicy,
+    pub format: PayloadFormat,
+}
+
+impl Sink for HttpSink {
+    fn kind(&self) -> SinkKind {
+        SinkKind::Http
+    }
+
+    /// Fails with a [`SendError`], which the caller downcasts to tell a lost
+    /// link and a rejected reading apart.
+    fn send(&mut self, state: &State) -> Result<()> {
+        Ok(upload(
+            &self.format.encode(state)?,
+            &self.endpoint,
+            &self.retry,
+        )?)
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<OffsetDateTime> {
+        Ok(OffsetDateTime::now_utc())
+    }
+
+    fn delay_ms(&self, ms: u32) {
+        FreeRtos::delay_ms(ms);
+    }
+}
+
+pub struct NvsQueueStorage {
+    pub nvs: EspNvs<NvsCustom>,
+}
+
+impl QueueStorage for NvsQueueStorage {
+    fn get_u32(&self, key: &str) -> Result<Option<u32>> {
+        Ok(self.nvs.get_u32(key)?)
+    }
+
+    fn set_u32(&mut self, key: &str, value: u32) -> Result<()> {
+        Ok(self.nvs.set_u32(key, value)?)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self
+            .nvs
+            .get_blob(key, &mut buf)?
+            .map(|value| value.to_vec()))
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        Ok(self.nvs.set_blob(key, value)?)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.nvs.remove(key)?;
+        Ok(())
+    }
+}
+
+const RADON_REPORT_KEY: &str = "radon_report";
+
+pub struct NvsStore {
+    pub nvs: EspNvs<NvsDefault>,
+}
+
+impl Store for NvsStore {
+    fn load_radon_reports(&self, serials: &[u32]) -> Result<Vec<(u32, OffsetDateTime)>> {
+        // Before radon was tracked per device a single time was kept for
+        // all of them.
+        let shared = self.load_time(RADON_REPORT_KEY)?;
+        let mut reports = Vec::new();
+        for serial in serials {
+            if let Some(at) = self.load_time(&radon_report_key(*serial))?.or(shared) {
+                reports.push((*serial, at));
+            }
+        }
+        Ok(reports)
+    }
+
+    fn save_radon_report(&mut self, serial: u32, at: OffsetDateTime) -> Result<()> {
+        Ok(self
+            .nvs
+            .set_i64(&radon_report_key(serial), at.unix_timestamp())?)
+    }
+
+    fn load_addresses(&self, serials: &[u32]) -> Result<Vec<(u32, Address)>> {
+        let mut addresses = Vec::new();
+        for serial in serials {
+            let mut buf = [0u8; 7];
+            let Some(blob) = self.nvs.get_blob(&address_key(*serial), &mut buf)? else {
+                continue;
+            };
+            match Address::from_bytes(blob) {
+                Some(address) => addresses.push((*serial, address)),
+                None => warn!("Ignoring invalid cached address for {}: {:?}", serial, blob),
+            }
+        }
+        Ok(addresses)
+    }
+
+    fn save_address(&mut self, serial: u32, address: &Address) -> Result<()> {
+        Ok(self
+            .nvs
+            .set_blob(&address_key(serial), &address.to_bytes())?)
+    }
+
+    fn remove_address(&mut self, serial: u32) -> Result<()> {
+        self.nvs.remove(&address_key(serial))?;
+        Ok(())
+    }
+}
+
+/// NVS keys are limited to 15 characters, which fits any `u32` serial.
+fn address_key(serial: u32) -> String {
+    format!("addr_{}", serial)
+}
+
+fn radon_report_key(serial: u32) -> String {
+    format!("rdn_{}", serial)
+}
+
+impl NvsStore {
+    fn load_time(&self, key: &str) -> Result<Option<OffsetDateTime>> {
+        match self.nvs.get_i64(key)? {
+            Some(timestamp) => Ok(Some(OffsetDateTime::from_unix_timestamp(timestamp)?)),
+            None => Ok(None),
+        }
+    }
+}
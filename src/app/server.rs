@@ -0,0 +1,75 @@
+use anyhow::Result;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use log::*;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::app::metrics::Metrics;
+use crate::waveplus::DiscoveredDevice;
+
+/// Start the device's HTTP server, exposing `/metrics` for Prometheus,
+/// `/devices` listing the Airthings devices last discovered, which a POST
+/// asks to refresh through `discover`, and, when updates are enabled,
+/// `/ota` to check for a firmware update. None of these are authenticated:
+/// `/ota` only makes the device fetch the configured manifest early, so
+/// anyone on the network can trigger a check but not choose the image.
+pub fn start(
+    port: u16,
+    metrics: Arc<Mutex<Metrics>>,
+    ota: Option<SyncSender<()>>,
+    discovered: Arc<Mutex<Vec<DiscoveredDevice>>>,
+    discover: SyncSender<()>,
+) -> Result<EspHttpServer<'static>> {
+    info!("Starting HTTP server on port {}", port);
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: port,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/metrics", Method::Get, move |request| {
+        let body = metrics
+            .lock()
+            .map(|metrics| metrics.render(Instant::now()))
+            .unwrap_or_default();
+        let mut response =
+            request.into_response(200, None, &[("content-type", "text/plain; version=0.0.4")])?;
+        response.write_all(body.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    server.fn_handler("/devices", Method::Get, move |request| {
+        let body = discovered
+            .lock()
+            .map(|discovered| serde_json::to_string(&*discovered))
+            .unwrap_or_else(|_| Ok("[]".to_string()))?;
+        request
+            .into_response(200, None, &[("content-type", "application/json")])?
+            .write_all(body.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    server.fn_handler("/devices", Method::Post, move |request| {
+        // The scan runs between reads, a pending one covers this request.
+        let _ = discover.try_send(());
+        request
+            .into_status_response(202)?
+            .write_all(b"Discovering Airthings devices")?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    if let Some(ota) = ota {
+        server.fn_handler("/ota", Method::Post, move |request| {
+            // A check that is already pending covers this request too.
+            let _ = ota.try_send(());
+            request
+                .into_status_response(202)?
+                .write_all(b"Checking for firmware update")?;
+            Ok::<(), anyhow::Error>(())
+        })?;
+    }
+
+    Ok(server)
+}
@@ -0,0 +1,245 @@
+//! TLS settings for uploads and firmware updates. They are read at boot from
+//! the dedicated `tls` NVS partition, so that certificates can be rotated by
+//! flashing that partition alone rather than rebuilding the firmware.
+
+use anyhow::{bail, Result};
+use esp_idf_svc::http::client::Configuration;
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, NvsCustom};
+use esp_idf_svc::sys::{self, esp};
+use esp_idf_svc::tls::X509;
+use log::*;
+use sha2::{Digest, Sha256};
+use std::ffi::{c_int, c_void};
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::app::fingerprint::parse_fingerprint;
+use crate::app::signing::hex;
+
+pub const PARTITION: &str = "tls";
+const NAMESPACE: &str = "tls";
+const CA_CERTS_KEY: &str = "ca_certs";
+const CA_MODE_KEY: &str = "ca_mode";
+const CLIENT_CERT_KEY: &str = "client_cert";
+const CLIENT_KEY_KEY: &str = "client_key";
+const FINGERPRINT_KEY: &str = "fingerprint";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The settings loaded at boot, which [`attach`] applies to each connection
+/// as ESP-TLS calls it without any context.
+static LOADED: OnceLock<Tls> = OnceLock::new();
+
+/// An mbedTLS certificate verification callback.
+type VerifyFn =
+    unsafe extern "C" fn(*mut c_void, *mut sys::mbedtls_x509_crt, c_int, *mut u32) -> c_int;
+
+/// Which certificate authorities servers are verified against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Trust {
+    /// The ESP-IDF certificate bundle.
+    #[default]
+    Bundle,
+    /// The stored CA certificates instead of the bundle.
+    Custom,
+    /// The stored CA certificates as well as the bundle.
+    BundleAndCustom,
+}
+
+/// Which servers to trust and how to authenticate to them.
+#[derive(Clone, Copy, Default)]
+pub struct Tls {
+    pub trust: Trust,
+    /// NUL terminated PEM client certificate and key for mutual TLS.
+    pub client_certificate: Option<&'static [u8]>,
+    pub private_key: Option<&'static [u8]>,
+    /// SHA-256 of the DER certificate the upload server must present.
+    pub fingerprint: Option<[u8; 32]>,
+}
+
+/// Only whether each setting is present is shown.
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("trust", &self.trust)
+            .field("client_certificate", &self.client_certificate.is_some())
+            .field("pinned", &self.fingerprint.is_some())
+            .finish()
+    }
+}
+
+impl Tls {
+    /// Load the settings stored in the `tls` partition, trusting the
+    /// certificate bundle alone when there is no such partition.
+    pub fn load() -> Result<Tls> {
+        let partition = match EspCustomNvsPartition::take(PARTITION) {
+            Ok(partition) => partition,
+            Err(err) => {
+                warn!(
+                    "No {} partition, trusting the certificate bundle: {:?}",
+                    PARTITION, err
+                );
+                return Ok(Tls::default());
+            }
+        };
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+
+        let mut tls = Tls::default();
+        if let Some(ca_certs) = read_pem(&nvs, CA_CERTS_KEY)? {
+            // The store keeps its own parsed copy of the certificates.
+            esp!(unsafe {
+                sys::esp_tls_set_global_ca_store(ca_certs.as_ptr(), ca_certs.len() as u32)
+            })?;
+            let mut buf = [0u8; 16];
+            tls.trust = match nvs.get_str(CA_MODE_KEY, &mut buf)?.map(str::trim) {
+                None | Some("") | Some("replace") => Trust::Custom,
+                Some("append") => Trust::BundleAndCustom,
+                Some(mode) => bail!("{} must be replace or append: {:?}", CA_MODE_KEY, mode),
+            };
+        }
+        // The HTTP client configuration borrows the PEMs for as long as the
+        // firmware runs, and they are only loaded once.
+        let leak = |pem: Vec<u8>| -> &'static [u8] { Box::leak(pem.into_boxed_slice()) };
+        tls.client_certificate = read_pem(&nvs, CLIENT_CERT_KEY)?.map(leak);
+        tls.private_key = read_pem(&nvs, CLIENT_KEY_KEY)?.map(leak);
+        if tls.client_certificate.is_some() != tls.private_key.is_some() {
+            bail!(
+                "{} and {} must be stored together",
+                CLIENT_CERT_KEY,
+                CLIENT_KEY_KEY
+            );
+        }
+        let mut buf = [0u8; 128];
+        if let Some(fingerprint) = nvs.get_str(FINGERPRINT_KEY, &mut buf)? {
+            tls.fingerprint = parse_fingerprint(fingerprint)?;
+        }
+
+        info!("Loaded TLS settings {:?}", tls);
+        let _ = LOADED.set(tls);
+        Ok(tls)
+    }
+
+    /// The same settings without the pinned certificate, for servers other
+    /// than the upload server.
+    pub fn unpinned(&self) -> Tls {
+        Tls {
+            fingerprint: None,
+            ..*self
+        }
+    }
+
+    /// The HTTP client configuration trusting the bundle, the stored CA
+    /// certificates or both, checking the pinned certificate if there is
+    /// one and presenting the client certificate if there is one.
+    pub fn configuration(&self) -> Configuration {
+        let attach: unsafe extern "C" fn(*mut c_void) -> sys::esp_err_t =
+            match (self.trust, self.fingerprint) {
+                (Trust::Bundle, None) => sys::esp_crt_bundle_attach,
+                (_, None) => attach,
+                (_, Some(_)) => attach_pinned,
+            };
+        Configuration {
+            crt_bundle_attach: Some(attach),
+            client_certificate: self.client_certificate.map(X509::pem_until_nul),
+            private_key: self.private_key.map(X509::pem_until_nul),
+            timeout: Some(TIMEOUT),
+            ..Default::default()
+        }
+    }
+}
+
+/// Set up certificate verification of a connection from the loaded
+/// settings. ESP-TLS calls this with the mbedTLS configuration of each
+/// connection, in place of the bundle's own attach function.
+unsafe extern "C" fn attach(conf: *mut c_void) -> sys::esp_err_t {
+    configure(conf.cast(), false)
+}
+
+/// As [`attach`], also checking the server certificate against the pin.
+unsafe extern "C" fn attach_pinned(conf: *mut c_void) -> sys::esp_err_t {
+    configure(conf.cast(), true)
+}
+
+unsafe fn configure(conf: *mut sys::mbedtls_ssl_config, pinned: bool) -> sys::esp_err_t {
+    let tls = LOADED.get().copied().unwrap_or_default();
+    if tls.trust != Trust::Custom {
+        let err = sys::esp_crt_bundle_attach(conf.cast());
+        if err != sys::ESP_OK {
+            return err;
+        }
+    }
+    // With the bundle attached, chains the stored certificates don't
+    // verify are still checked against the bundle.
+    if tls.trust != Trust::Bundle {
+        sys::mbedtls_ssl_conf_ca_chain(
+            conf,
+            sys::esp_tls_get_global_ca_store(),
+            std::ptr::null_mut(),
+        );
+    }
+    sys::mbedtls_ssl_conf_authmode(conf, sys::MBEDTLS_SSL_VERIFY_REQUIRED as _);
+    if pinned {
+        // The pin is checked after the bundle's own verification, which is
+        // registered without a context and passed on as ours.
+        let bundle = (*conf).private_f_vrfy;
+        let context = bundle.map_or(std::ptr::null_mut(), |verify| verify as *mut c_void);
+        sys::mbedtls_ssl_conf_verify(conf, Some(verify_pin), context);
+    }
+    sys::ESP_OK
+}
+
+/// Run the bundle's verification passed as `context`, if any, then reject
+/// a server certificate other than the pinned one. This is called during
+/// the handshake of the connection the request is sent over.
+unsafe extern "C" fn verify_pin(
+    context: *mut c_void,
+    certificate: *mut sys::mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    if !context.is_null() {
+        let bundle: VerifyFn = std::mem::transmute(context);
+        let ret = bundle(std::ptr::null_mut(), certificate, depth, flags);
+        if ret != 0 {
+            return ret;
+        }
+    }
+    // Depth 0 is the server's own certificate, the others its chain.
+    let Some(pinned) = LOADED.get().and_then(|tls| tls.fingerprint) else {
+        return 0;
+    };
+    if depth != 0 {
+        return 0;
+    }
+    let der = std::slice::from_raw_parts((*certificate).raw.p, (*certificate).raw.len);
+    let fingerprint: [u8; 32] = Sha256::digest(der).into();
+    if fingerprint != pinned {
+        error!(
+            "Server certificate has fingerprint {}, expected {}",
+            hex(&fingerprint),
+            hex(&pinned)
+        );
+        *flags |= sys::MBEDTLS_X509_BADCERT_NOT_TRUSTED;
+    }
+    0
+}
+
+/// A PEM stored as a blob, NUL terminated as mbedTLS expects.
+fn read_pem(nvs: &EspNvs<NvsCustom>, key: &str) -> Result<Option<Vec<u8>>> {
+    let Some(len) = nvs.blob_len(key)? else {
+        return Ok(None);
+    };
+    let mut buf = vec![0u8; len];
+    let mut pem = nvs.get_blob(key, &mut buf)?.unwrap_or_default().to_vec();
+    if pem.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    if !pem.starts_with(b"-----BEGIN") {
+        bail!("{} is not a PEM", key);
+    }
+    if pem.last() != Some(&0) {
+        pem.push(0);
+    }
+    Ok(Some(pem))
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use log::*;
+
+use crate::Config;
+
+pub use waveplus_core::config::{migrate, parse_serials, DeviceConfig, VERSION};
+
+pub const NAMESPACE: &str = "config";
+const KEY: &str = "config";
+
+/// The compile-time configuration from `cfg.toml`.
+pub fn defaults(config: &Config) -> Result<DeviceConfig> {
+    Ok(DeviceConfig {
+        version: VERSION,
+        wifi_ssid: config.wifi_ssid.to_string(),
+        wifi_psk: config.wifi_psk.to_string(),
+        // Configurations written for a single device name it
+        // `waveplus_serial`.
+        waveplus_serials: parse_serials(if config.waveplus_serials.is_empty() {
+            config.waveplus_serial
+        } else {
+            config.waveplus_serials
+        })?,
+        read_interval: config.read_interval,
+        radon_interval: config.radon_interval,
+        radon_offset: config.radon_offset,
+        scan_interval: config.scan_interval,
+        scan_window: config.scan_window,
+        scan_timeout: config.scan_timeout,
+        min_read_interval: config.min_read_interval,
+        queue_capacity: config.queue_capacity,
+        batch_size: config.batch_size,
+        batch_max_bytes: config.batch_max_bytes,
+        server: config.server.to_string(),
+        output_format: config.output_format.to_string(),
+        sinks: config.sinks.to_string(),
+        influx_org: config.influx_org.to_string(),
+        influx_bucket: config.influx_bucket.to_string(),
+        influx_token: config.influx_token.to_string(),
+        auth_type: config.auth_type.to_string(),
+        auth_token: config.auth_token.to_string(),
+        auth_username: config.auth_username.to_string(),
+        auth_password: config.auth_password.to_string(),
+        http_headers: config.http_headers.to_string(),
+        signing_secret: config.signing_secret.to_string(),
+        ntp_server: config.ntp_server.to_string(),
+        http_retries: config.http_retries,
+        http_retry_delay: config.http_retry_delay,
+        http_port: config.http_port,
+        ota_url: config.ota_url.to_string(),
+        ota_interval: config.ota_interval,
+        mqtt_url: config.mqtt_url.to_string(),
+        mqtt_client_id: config.mqtt_client_id.to_string(),
+        mqtt_username: config.mqtt_username.to_string(),
+        mqtt_password: config.mqtt_password.to_string(),
+        mqtt_prefix: config.mqtt_prefix.to_string(),
+        mqtt_discovery_prefix: config.mqtt_discovery_prefix.to_string(),
+    })
+}
+
+/// Load the stored configuration, migrating it to the current schema
+/// and storing the defaults if there is none yet.
+pub fn load(nvs: &mut EspNvs<NvsDefault>, defaults: DeviceConfig) -> Result<DeviceConfig> {
+    let Some(len) = nvs.blob_len(KEY)? else {
+        info!("No stored configuration, using defaults");
+        defaults.validate()?;
+        save(&defaults, nvs)?;
+        return Ok(defaults);
+    };
+
+    let mut buf = vec![0u8; len];
+    let stored = nvs.get_blob(KEY, &mut buf)?.unwrap_or_default();
+    let config = serde_json::from_slice(stored)
+        .map_err(anyhow::Error::from)
+        .and_then(|stored| migrate(stored, &defaults))
+        .and_then(|config| config.validate().map(|_| config));
+
+    match config {
+        Ok(config) => {
+            if stored != serde_json::to_vec(&config)?.as_slice() {
+                info!("Saving migrated configuration");
+                save(&config, nvs)?;
+            }
+            Ok(config)
+        }
+        Err(err) => {
+            error!("Invalid stored configuration, using defaults: {:?}", err);
+            defaults.validate()?;
+            Ok(defaults)
+        }
+    }
+}
+
+pub fn save(config: &DeviceConfig, nvs: &mut EspNvs<NvsDefault>) -> Result<()> {
+    config.validate()?;
+    nvs.set_blob(KEY, &serde_json::to_vec(config)?)?;
+    Ok(())
+}
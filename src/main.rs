@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::prelude::Peripherals,
@@ -9,20 +9,23 @@ use log::*;
 
 use esp_idf_svc::hal::delay::FreeRtos;
 use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspDefaultNvsPartition, EspNvs};
 use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
 use esp_idf_svc::sys::{esp, esp_wifi_connect};
 
 mod app;
+mod config;
+mod provision;
 mod rgbled;
-mod utils;
 mod waveplus;
 mod wifi;
 
-use rgbled::{RGB8, WS2812RMT};
+use rgbled::WS2812RMT;
 use wifi::{connect_wifi, wait_for_connected};
 
 /// This configuration is picked up at compile time by `build.rs` from the
-/// file `cfg.toml`.
+/// file `cfg.toml`, and used as the defaults for the [`config::DeviceConfig`]
+/// stored in NVS on first boot.
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -30,13 +33,77 @@ pub struct Config {
     #[default("")]
     wifi_psk: &'static str,
     #[default("")]
+    waveplus_serials: &'static str,
+    #[default("")]
     waveplus_serial: &'static str,
     #[default(30)]
     read_interval: u16,
+    #[default(60)]
+    radon_interval: u32,
+    #[default(0)]
+    radon_offset: u32,
+    #[default(100)]
+    scan_interval: u16,
+    #[default(99)]
+    scan_window: u16,
+    #[default(10000)]
+    scan_timeout: u32,
+    #[default(0)]
+    min_read_interval: u32,
+    #[default(256)]
+    queue_capacity: u16,
+    #[default(10)]
+    batch_size: u16,
+    #[default(8192)]
+    batch_max_bytes: u32,
     #[default("")]
     server: &'static str,
+    #[default("json")]
+    output_format: &'static str,
+    #[default("http,mqtt")]
+    sinks: &'static str,
+    #[default("")]
+    influx_org: &'static str,
+    #[default("")]
+    influx_bucket: &'static str,
+    #[default("")]
+    influx_token: &'static str,
+    #[default("none")]
+    auth_type: &'static str,
+    #[default("")]
+    auth_token: &'static str,
+    #[default("")]
+    auth_username: &'static str,
+    #[default("")]
+    auth_password: &'static str,
+    #[default("")]
+    http_headers: &'static str,
+    #[default("")]
+    signing_secret: &'static str,
     #[default("pool.ntp.org")]
     ntp_server: &'static str,
+    #[default(3)]
+    http_retries: u32,
+    #[default(1000)]
+    http_retry_delay: u32,
+    #[default(80)]
+    http_port: u16,
+    #[default("")]
+    ota_url: &'static str,
+    #[default(86400)]
+    ota_interval: u32,
+    #[default("")]
+    mqtt_url: &'static str,
+    #[default("waveplus-reader")]
+    mqtt_client_id: &'static str,
+    #[default("")]
+    mqtt_username: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    #[default("waveplus")]
+    mqtt_prefix: &'static str,
+    #[default("homeassistant")]
+    mqtt_discovery_prefix: &'static str,
 }
 
 fn main() -> Result<()> {
@@ -47,18 +114,44 @@ fn main() -> Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    let app_config = CONFIG;
+    let nvs_partition = EspDefaultNvsPartition::take()?;
+    let mut config_nvs = EspNvs::new(nvs_partition.clone(), config::NAMESPACE, true)?;
+    let app_config = config::load(&mut config_nvs, config::defaults(&CONFIG)?)?;
 
     let peripherals = Peripherals::take().unwrap();
 
     // Start the LED off yellow
     let mut led = WS2812RMT::new(peripherals.pins.gpio8, peripherals.rmt.channel0)?;
-    led.set_pixel(RGB8::from(app::Status::Initializing))?;
+    led.set_pixel(app::color(app::Status::Initializing))?;
 
     let sysloop = EspSystemEventLoop::take()?;
 
-    if app_config.wifi_ssid.is_empty() {
-        bail!("Missing WiFi name")
+    let scan = waveplus::ScanSettings {
+        interval: app_config.scan_interval,
+        window: app_config.scan_window,
+        timeout: i32::try_from(app_config.scan_timeout)?,
+    };
+    // Discovery scans for the whole timeout, so at boot it only runs when
+    // there is no sensor to read yet. Otherwise `/devices` runs it on
+    // request.
+    let discovered = if app_config.wifi_ssid.is_empty() || app_config.waveplus_serials.is_empty() {
+        waveplus::discover(&scan).unwrap_or_else(|err| {
+            error!("Failed to discover Airthings devices: {:?}", err);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    if !app_config.is_provisioned() {
+        warn!("Missing WiFi name or Wave Plus serial, starting provisioning access point");
+        return provision::run(
+            peripherals.modem,
+            sysloop,
+            config_nvs,
+            app_config,
+            discovered,
+        );
     }
 
     info!("SSID: {:?}", app_config.wifi_ssid);
@@ -68,8 +161,8 @@ fn main() -> Result<()> {
         sysloop.clone(),
         None,
         AuthMethod::WPA2Personal,
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
+        &app_config.wifi_ssid,
+        &app_config.wifi_psk,
     )?;
 
     info!("Subscribing to events");
@@ -96,20 +189,61 @@ fn main() -> Result<()> {
     // SNTP
 
     let sntp_conf = SntpConf::<'_> {
-        servers: [app_config.ntp_server],
+        servers: [app_config.ntp_server.as_str()],
         ..Default::default()
     };
     let sntp = EspSntp::new(&sntp_conf)?;
 
     wait_for_sntp(&sntp);
 
-    let serial: u32 = app_config.waveplus_serial.parse()?;
+    // Measurements that could not be uploaded are kept in a dedicated
+    // partition so that they survive a reboot.
+    let queue_partition = EspCustomNvsPartition::take("queue")?;
+    let queue_nvs = EspNvs::new(queue_partition, "queue", true)?;
+
+    let settings = app::Settings {
+        serials: app_config.waveplus_serials.clone(),
+        server: &app_config.server,
+        format: app_config.output_format.parse()?,
+        sinks: app::parse_sinks(&app_config.sinks)?,
+        influx: app::InfluxSettings {
+            org: &app_config.influx_org,
+            bucket: &app_config.influx_bucket,
+            token: &app_config.influx_token,
+        },
+        read_interval: app_config.read_interval,
+        radon: app::RadonSchedule {
+            interval_minutes: app_config.radon_interval,
+            offset_minutes: app_config.radon_offset,
+        },
+        scan,
+        min_read_interval: app_config.min_read_interval,
+        queue_capacity: app_config.queue_capacity,
+        batch_size: app_config.batch_size,
+        batch_max_bytes: app_config.batch_max_bytes,
+        mqtt: (!app_config.mqtt_url.is_empty()).then_some(app::MqttSettings {
+            url: &app_config.mqtt_url,
+            client_id: &app_config.mqtt_client_id,
+            username: &app_config.mqtt_username,
+            password: &app_config.mqtt_password,
+            prefix: &app_config.mqtt_prefix,
+            discovery_prefix: &app_config.mqtt_discovery_prefix,
+        }),
+        auth: app_config.auth()?,
+        headers: app::parse_headers(&app_config.http_headers)?,
+        signing_secret: &app_config.signing_secret,
+        tls: app::Tls::load()?,
+        http_retries: app_config.http_retries,
+        http_retry_delay: app_config.http_retry_delay,
+        http_port: app_config.http_port,
+        ota_url: &app_config.ota_url,
+        ota_interval: app_config.ota_interval,
+    };
+
+    let state_nvs = EspNvs::new(nvs_partition, "state", true)?;
+
     app::run(
-        &mut wifi,
-        &mut led,
-        serial,
-        app_config.server,
-        app_config.read_interval,
+        &mut wifi, &mut led, queue_nvs, state_nvs, &settings, discovered,
     )
 }
 
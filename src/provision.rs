@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use embedded_svc::http::Method;
+use embedded_svc::io::{Read, Write};
+use esp_idf_svc::hal::{delay::FreeRtos, modem::WifiModemPeripheral, peripheral::Peripheral};
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    wifi::{
+        AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, Configuration,
+        EspWifi,
+    },
+};
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{self, parse_serials, DeviceConfig};
+use crate::waveplus::DiscoveredDevice;
+use waveplus_core::provision::{dns, form};
+
+const AP_SSID: &str = "waveplus-reader";
+/// The default address of the ESP-IDF SoftAP interface.
+const AP_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+const MAX_BODY_LEN: usize = 2048;
+
+/// Render the provisioning form, prefilled from `config`.
+fn render_form(
+    config: &DeviceConfig,
+    networks: &[String],
+    discovered: &[DiscoveredDevice],
+    message: &str,
+) -> String {
+    let options: String = networks
+        .iter()
+        .map(|ssid| {
+            let ssid = form::escape_html(ssid);
+            format!("<option value=\"{}\">{}</option>", ssid, ssid)
+        })
+        .collect();
+    let nearby: String = discovered
+        .iter()
+        .map(|device| {
+            format!(
+                "<li>{} ({}, {} dBm)</li>",
+                device.serial_number,
+                device.model.name(),
+                device.rssi
+            )
+        })
+        .collect();
+    let serials: Vec<String> = config
+        .waveplus_serials
+        .iter()
+        .map(|serial| serial.to_string())
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Wave Plus reader setup</title></head>
+<body><h1>Wave Plus reader setup</h1><p>{message}</p>
+<form method="post" action="/save">
+<p><label>Network<br><input name="ssid" list="networks" value="{ssid}" required></label>
+<datalist id="networks">{options}</datalist></p>
+<p><label>Password<br><input name="psk" type="password"></label></p>
+<p><label>Wave Plus serials (comma separated)<br><input name="waveplus_serials" value="{serials}" required></label></p>
+<p>Nearby Airthings devices:</p><ul>{nearby}</ul>
+<p><label>Server URL<br><input name="server" type="url" value="{server}"></label></p>
+<p><label>Read interval (seconds)<br><input name="read_interval" type="number" min="1" value="{read_interval}"></label></p>
+<p><button type="submit">Save and reboot</button></p>
+</form></body></html>"#,
+        message = form::escape_html(message),
+        ssid = form::escape_html(&config.wifi_ssid),
+        options = options,
+        serials = serials.join(","),
+        nearby = nearby,
+        server = form::escape_html(&config.server),
+        read_interval = config.read_interval,
+    )
+}
+
+/// Apply the submitted form to `config`.
+fn apply_form(config: &DeviceConfig, body: &str) -> Result<DeviceConfig> {
+    let fields = form::parse(body);
+    let field = |name: &str| fields.get(name).map(|value| value.trim().to_string());
+
+    let mut config = config.clone();
+    config.wifi_ssid = field("ssid")
+        .filter(|ssid| !ssid.is_empty())
+        .ok_or_else(|| anyhow!("A network name is required"))?;
+    config.wifi_psk = fields.get("psk").cloned().unwrap_or_default();
+    if let Some(serials) = field("waveplus_serials") {
+        config.waveplus_serials = parse_serials(&serials)?;
+    }
+    if config.waveplus_serials.is_empty() {
+        return Err(anyhow!("At least one Wave Plus serial is required"));
+    }
+    if let Some(server) = field("server") {
+        config.server = server;
+    }
+    if let Some(read_interval) = field("read_interval") {
+        config.read_interval = read_interval.parse()?;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn read_body(request: &mut impl Read) -> Result<String> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = request
+            .read(&mut buf)
+            .map_err(|err| anyhow!("Failed to read request: {:?}", err))?;
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..len]);
+        if body.len() > MAX_BODY_LEN {
+            return Err(anyhow!("Request body too large"));
+        }
+    }
+    Ok(String::from_utf8(body)?)
+}
+
+/// Answer every DNS query with the address of the access point, so that
+/// phones and laptops open the form as a captive portal.
+fn spawn_dns() -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, peer)) => {
+                        if let Some(response) = dns::response(&buf[..len], AP_IP) {
+                            if let Err(err) = socket.send_to(&response, peer) {
+                                warn!("Failed to send DNS response: {:?}", err);
+                            }
+                        }
+                    }
+                    Err(err) => warn!("Failed to receive DNS query: {:?}", err),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Run a SoftAP with a captive portal where the Wi-Fi credentials and
+/// other settings can be entered.  Once they are saved the device reboots
+/// into station mode.
+pub fn run<'d>(
+    modem: impl Peripheral<P = impl WifiModemPeripheral + 'd> + 'd,
+    sysloop: EspSystemEventLoop,
+    nvs: EspNvs<NvsDefault>,
+    config: DeviceConfig,
+    discovered: Vec<DiscoveredDevice>,
+) -> Result<()> {
+    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sysloop.clone(), None)?, sysloop)?;
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid: AP_SSID.try_into().expect("Could not parse AP SSID"),
+            auth_method: AuthMethod::None,
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
+
+    info!("Scanning for networks");
+    let mut networks: Vec<String> = wifi
+        .scan()?
+        .into_iter()
+        .map(|network| network.ssid.to_string())
+        .filter(|ssid| !ssid.is_empty())
+        .collect();
+    networks.sort();
+    networks.dedup();
+
+    info!("Started access point {:?} on {}", AP_SSID, AP_IP);
+    spawn_dns()?;
+
+    let config = Arc::new(Mutex::new(config));
+    let nvs = Arc::new(Mutex::new(nvs));
+    let saved = Arc::new(AtomicBool::new(false));
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let devices = serde_json::to_string(&discovered)?;
+    let form_config = config.clone();
+    let form_networks = networks.clone();
+    let form_discovered = discovered.clone();
+    server.fn_handler("/", Method::Get, move |request| {
+        let config = form_config.lock().map_err(|_| anyhow!("Config lock"))?;
+        let body = render_form(&config, &form_networks, &form_discovered, "");
+        request.into_ok_response()?.write_all(body.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    let save_saved = saved.clone();
+    server.fn_handler("/save", Method::Post, move |mut request| {
+        let body = read_body(&mut request)?;
+        let mut current = config.lock().map_err(|_| anyhow!("Config lock"))?;
+        let result = apply_form(&current, &body).and_then(|updated| {
+            let mut nvs = nvs.lock().map_err(|_| anyhow!("NVS lock"))?;
+            config::save(&updated, &mut nvs)?;
+            Ok(updated)
+        });
+        match result {
+            Ok(updated) => {
+                *current = updated;
+                request
+                    .into_ok_response()?
+                    .write_all(b"Configuration saved, rebooting.")?;
+                save_saved.store(true, Ordering::SeqCst);
+            }
+            Err(err) => {
+                warn!("Rejected configuration: {:?}", err);
+                let body = render_form(&current, &networks, &discovered, &err.to_string());
+                request
+                    .into_status_response(400)?
+                    .write_all(body.as_bytes())?;
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    server.fn_handler("/devices", Method::Get, move |request| {
+        request
+            .into_response(200, None, &[("content-type", "application/json")])?
+            .write_all(devices.as_bytes())?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    // Send anything else, such as OS connectivity checks, to the form.
+    let location = format!("http://{}/", AP_IP);
+    server.fn_handler("/*", Method::Get, move |request| {
+        request.into_response(302, Some("Found"), &[("location", location.as_str())])?;
+        Ok::<(), anyhow::Error>(())
+    })?;
+
+    while !saved.load(Ordering::SeqCst) {
+        FreeRtos::delay_ms(500);
+    }
+
+    info!("Configuration saved, rebooting");
+    FreeRtos::delay_ms(1000);
+    esp_idf_svc::hal::reset::restart();
+}
@@ -1,12 +1,18 @@
 use anyhow::{anyhow, Result};
 use bincode::Options;
-use esp32_nimble::{uuid128, BLEAdvertisedDevice, BLEClient, BLEDevice, BLEScan};
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{uuid128, BLEAddress, BLEAddressType, BLEClient, BLEDevice, BLEScan};
 use esp_idf_svc::hal::task::block_on;
 use log::*;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::time::Instant;
 
-pub mod measurement;
+pub mod command;
 
-use measurement::{WavePlusManufacturerInfo, WavePlusMeasurement, WavePlusRawMeasurementData};
+pub use waveplus_core::waveplus::{address, measurement, model};
+
+use address::Address;
+use measurement::{LinkDiagnostics, Measurement, WavePlusManufacturerInfo};
 
 macro_rules! bincode_options {
     () => {
@@ -17,72 +23,262 @@ macro_rules! bincode_options {
     };
 }
 
-fn parse_value(value: &Vec<u8>) -> Result<WavePlusRawMeasurementData> {
-    if value.len() != 20 {
-        return Err(anyhow!("Unexpected BLE packet {:?}", value));
+use model::Model;
+
+/// Airthings' Bluetooth SIG company identifier.
+const AIRTHINGS_COMPANY_ID: u16 = 0x0334;
+
+/// Length of the serial number and reserved bytes starting the
+/// manufacturer data of an Airthings advertisement.
+const MANUFACTURER_INFO_LEN: usize = 6;
+
+/// The GATT services and characteristics of each model, kept with the BLE
+/// code so that the model itself doesn't depend on the BLE stack.
+pub trait Gatt {
+    fn service_uuid(&self) -> BleUuid;
+
+    fn characteristic_uuid(&self) -> BleUuid;
+
+    fn command_uuid(&self) -> Option<BleUuid>;
+}
+
+impl Gatt for Model {
+    fn service_uuid(&self) -> BleUuid {
+        match self {
+            Model::WavePlus => uuid128!("b42e1c08-ade7-11e4-89d3-123b93f75cba"),
+            Model::WaveRadon => uuid128!("b42e4a8e-ade7-11e4-89d3-123b93f75cba"),
+            Model::WaveMini => uuid128!("b42e3882-ade7-11e4-89d3-123b93f75cba"),
+            Model::ViewPlus => uuid128!("b42e90a2-ade7-11e4-89d3-123b93f75cba"),
+        }
     }
-    let raw: WavePlusRawMeasurementData = bincode_options!().deserialize(value).unwrap();
-    Ok(raw)
+
+    fn characteristic_uuid(&self) -> BleUuid {
+        match self {
+            Model::WavePlus => uuid128!("b42e2a68-ade7-11e4-89d3-123b93f75cba"),
+            Model::WaveRadon => uuid128!("b42e4dcc-ade7-11e4-89d3-123b93f75cba"),
+            Model::WaveMini => uuid128!("b42e3b98-ade7-11e4-89d3-123b93f75cba"),
+            Model::ViewPlus => uuid128!("b42eb4a6-ade7-11e4-89d3-123b93f75cba"),
+        }
+    }
+
+    /// The command characteristic, written to and answered with a
+    /// notification. Only the Wave Plus command protocol is supported.
+    fn command_uuid(&self) -> Option<BleUuid> {
+        match self {
+            Model::WavePlus => Some(uuid128!("b42e2d06-ade7-11e4-89d3-123b93f75cba")),
+            Model::WaveRadon | Model::WaveMini | Model::ViewPlus => None,
+        }
+    }
+}
+
+fn from_ble(address: BLEAddress) -> Address {
+    Address {
+        bytes: address.as_le_bytes(),
+        kind: address.addr_type() as u8,
+    }
+}
+
+fn to_ble(address: &Address) -> BLEAddress {
+    let addr_type = match address.kind {
+        0 => BLEAddressType::Public,
+        1 => BLEAddressType::Random,
+        2 => BLEAddressType::PublicID,
+        _ => BLEAddressType::RandomID,
+    };
+    BLEAddress::from_le_bytes(address.bytes, addr_type)
 }
 
-pub fn get_waveplus(serial_number: &u32) -> Result<BLEAdvertisedDevice> {
-    info!("Scanning for Wave Plus devices");
+/// How the BLE scan for devices without a known address is run.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanSettings {
+    /// Milliseconds between the start of two scan windows.
+    pub interval: u16,
+    /// Milliseconds spent listening in each interval.
+    pub window: u16,
+    /// Milliseconds after which the scan gives up on missing devices.
+    pub timeout: i32,
+}
+
+pub fn get_waveplus(
+    serial_numbers: &[u32],
+    settings: &ScanSettings,
+) -> Result<Vec<(u32, Address)>> {
+    info!("Scanning for Wave Plus devices {:?}", serial_numbers);
     block_on(async {
         let ble_device = BLEDevice::take();
         let mut ble_scan = BLEScan::new();
-        let device = ble_scan
+        let mut found: Vec<(u32, Address)> = Vec::new();
+        ble_scan
             .active_scan(true)
-            .interval(100)
-            .window(99)
-            .start(ble_device, 10000, |device, data| {
+            .interval(settings.interval)
+            .window(settings.window)
+            .start(ble_device, settings.timeout, |device, data| {
                 if let Some(manufacture_data) = data.manufacture_data() {
-                    if manufacture_data.company_identifier != 0x0334 {
-                        return None::<BLEAdvertisedDevice>;
+                    let Some(serial_number) = decode_advertisement(
+                        device.addr(),
+                        manufacture_data.company_identifier,
+                        manufacture_data.payload,
+                    ) else {
+                        return None::<()>;
+                    };
+                    if serial_numbers.contains(&serial_number)
+                        && !found.iter().any(|(serial, _)| *serial == serial_number)
+                    {
+                        info!("Found Wave Plus {:?}", serial_number);
+                        found.push((serial_number, from_ble(device.addr())));
                     }
-                    let mfg: WavePlusManufacturerInfo = bincode_options!()
-                        .deserialize(manufacture_data.payload)
-                        .unwrap();
-                    if mfg.serial_number == *serial_number {
-                        return Some(*device);
+                    if found.len() == serial_numbers.len() {
+                        return Some(());
                     }
                 }
-                None::<BLEAdvertisedDevice>
+                None::<()>
             })
             .await?;
 
-        if let Some(device) = device {
-            Ok(device)
-        } else {
-            Err(anyhow!(
-                "Could not find Wave Plus with serial {:?}",
-                serial_number
-            ))
+        for serial_number in serial_numbers {
+            if !found.iter().any(|(serial, _)| serial == serial_number) {
+                warn!("Could not find Wave Plus with serial {:?}", serial_number);
+            }
         }
+
+        Ok(found)
     })
 }
 
+/// An Airthings device heard during discovery.
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveredDevice {
+    pub serial_number: u32,
+    pub model: Model,
+    pub address: Address,
+    pub rssi: i32,
+}
+
+impl Serialize for DiscoveredDevice {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("DiscoveredDevice", 4)?;
+        state.serialize_field("serial_number", &self.serial_number.to_string())?;
+        state.serialize_field("model", self.model.name())?;
+        state.serialize_field("address", &self.address.to_string())?;
+        state.serialize_field("rssi", &self.rssi)?;
+        state.end()
+    }
+}
+
+/// Scan for the whole timeout and list every Airthings device heard,
+/// strongest signal first, so that the sensor to bind can be picked.
+pub fn discover(settings: &ScanSettings) -> Result<Vec<DiscoveredDevice>> {
+    info!("Discovering Airthings devices");
+    block_on(async {
+        let ble_device = BLEDevice::take();
+        let mut ble_scan = BLEScan::new();
+        let mut discovered: Vec<DiscoveredDevice> = Vec::new();
+        ble_scan
+            .active_scan(true)
+            .interval(settings.interval)
+            .window(settings.window)
+            .start(ble_device, settings.timeout, |device, data| {
+                let manufacture_data = data.manufacture_data()?;
+                let serial_number = decode_advertisement(
+                    device.addr(),
+                    manufacture_data.company_identifier,
+                    manufacture_data.payload,
+                )?;
+                let seen = DiscoveredDevice {
+                    serial_number,
+                    model: Model::from_serial(serial_number),
+                    address: from_ble(device.addr()),
+                    rssi: device.rssi(),
+                };
+                match discovered
+                    .iter_mut()
+                    .find(|known| known.serial_number == serial_number)
+                {
+                    Some(known) => *known = seen,
+                    None => discovered.push(seen),
+                }
+                None::<()>
+            })
+            .await?;
+
+        discovered.sort_by_key(|device| -device.rssi);
+        info!("Found {} Airthings devices", discovered.len());
+        for device in discovered.iter() {
+            info!(
+                "  serial {} {} at {}, RSSI {} dBm",
+                device.serial_number,
+                device.model.name(),
+                device.address,
+                device.rssi
+            );
+        }
+        Ok(discovered)
+    })
+}
+
+/// The serial number in the manufacturer data of an Airthings advertisement.
+/// Advertisements that can't be decoded are logged and skipped.
+fn decode_advertisement(
+    address: BLEAddress,
+    company_identifier: u16,
+    payload: &[u8],
+) -> Option<u32> {
+    if company_identifier != AIRTHINGS_COMPANY_ID {
+        return None;
+    }
+    let Some(info) = payload.get(..MANUFACTURER_INFO_LEN) else {
+        warn!(
+            "Skipping short Airthings advertisement from {}: {:?}",
+            address, payload
+        );
+        return None;
+    };
+    match bincode_options!().deserialize::<WavePlusManufacturerInfo>(info) {
+        Ok(mfg) => Some(mfg.serial_number),
+        Err(err) => {
+            warn!(
+                "Skipping Airthings advertisement from {}: {:?}",
+                address, err
+            );
+            None
+        }
+    }
+}
+
 pub fn read_waveplus(
     serial_number: u32,
-    waveplus: &BLEAdvertisedDevice,
+    address: &Address,
     include_radon: bool,
-) -> Result<WavePlusMeasurement> {
+) -> Result<Measurement> {
+    let model = Model::from_serial(serial_number);
     info!(
-        "Scraping measurement from {:?}: {:?}",
-        serial_number, waveplus
+        "Scraping measurement from {} {:?} at {}",
+        model.name(),
+        serial_number,
+        address
     );
     block_on(async {
         let mut client = BLEClient::new();
         client.on_connect(|client| {
             client.update_conn_params(120, 120, 0, 60).unwrap();
         });
-        client.connect(&waveplus.addr()).await?;
-
-        let service_uuid = uuid128!("b42e1c08-ade7-11e4-89d3-123b93f75cba");
-        let characteristic_uuid = uuid128!("b42e2a68-ade7-11e4-89d3-123b93f75cba");
+        let started = Instant::now();
+        client.connect(&to_ble(address)).await?;
+        let connected = Instant::now();
+        let connection_rssi = client
+            .get_rssi()
+            .map_err(|err| warn!("Failed to get RSSI of {:?}: {:?}", serial_number, err))
+            .ok();
 
-        let service = client.get_service(service_uuid).await?;
+        let service = client.get_service(model.service_uuid()).await?;
 
-        let characteristic = service.get_characteristic(characteristic_uuid).await?;
+        let characteristic = service
+            .get_characteristic(model.characteristic_uuid())
+            .await?;
+        let discovered = Instant::now();
 
         if !characteristic.can_read() {
             error!("characteristic can't read: {}", characteristic);
@@ -91,17 +287,40 @@ pub fn read_waveplus(
         }
 
         let raw_value = characteristic.read_value().await;
+        let read = Instant::now();
+        let link = LinkDiagnostics {
+            connection_rssi: connection_rssi.map(i32::from),
+            connect_ms: Some(elapsed_ms(started, connected)),
+            discover_ms: Some(elapsed_ms(connected, discovered)),
+            read_ms: Some(elapsed_ms(discovered, read)),
+        };
+        info!("Link to {:?}: {:?}", serial_number, link);
+
+        // A missing battery level should not cost us the measurement.
+        let battery = match model.command_uuid() {
+            Some(_) => command::read_battery(&mut client, model)
+                .await
+                .map_err(|err| warn!("Failed to read battery of {:?}: {:?}", serial_number, err))
+                .ok(),
+            None => None,
+        };
 
         client.disconnect()?;
 
         match raw_value {
             Ok(value) => {
-                let raw = parse_value(&value)?;
-                let measurement =
-                    WavePlusMeasurement::new(serial_number, waveplus.addr(), &raw, include_radon);
+                let data = model.decode(&value)?;
+                let mut measurement =
+                    Measurement::new(serial_number, model, *address, data, include_radon);
+                measurement.battery = battery;
+                measurement.link = link;
                 Ok(measurement)
             }
             Err(_) => Err(anyhow!("Failed to read measurement")),
         }
     })
 }
+
+fn elapsed_ms(from: Instant, to: Instant) -> u32 {
+    u32::try_from(to.duration_since(from).as_millis()).unwrap_or(u32::MAX)
+}
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use esp32_nimble::BLEClient;
+use log::*;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::waveplus::measurement::Battery;
+use crate::waveplus::model::Model;
+use crate::waveplus::Gatt;
+
+/// Requests the readings of all sensors together with diagnostics such as
+/// the battery voltage.
+pub const MULTI_SENSOR: u8 = 0x6d;
+
+/// How long to wait for the notification answering a command.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The multi-sensor response is the command byte, a reserved byte, then a
+/// `u32`, twelve `u8` and six little endian `u16` values.
+const MULTI_SENSOR_LEN: usize = 2 + 4 + 12 + 6 * 2;
+
+/// Offset of the battery voltage in millivolts in the multi-sensor response.
+const BATTERY_OFFSET: usize = 2 + 4 + 12 + 4 * 2;
+
+/// Write `payload` to the command characteristic (the access control
+/// point) of the connected device and collect the notifications answering
+/// it until `complete` holds for the response received so far.
+pub async fn request(
+    client: &mut BLEClient,
+    model: Model,
+    payload: &[u8],
+    complete: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>> {
+    let command = payload[0];
+    let uuid = model
+        .command_uuid()
+        .ok_or_else(|| anyhow!("{} has no command characteristic", model.name()))?;
+    let service = client.get_service(model.service_uuid()).await?;
+    let characteristic = service.get_characteristic(uuid).await?;
+
+    if !characteristic.can_notify() {
+        return Err(anyhow!("Command characteristic can't notify"));
+    }
+
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    characteristic.on_notify(move |data| {
+        let _ = sender.send(data.to_vec());
+    });
+    characteristic.subscribe_notify(false).await?;
+    let response = async {
+        characteristic.write_value(payload, true).await?;
+        let mut response = Vec::new();
+        while !complete(&response) {
+            let chunk = receiver
+                .recv_timeout(RESPONSE_TIMEOUT)
+                .map_err(|_| anyhow!("Timed out waiting for response to command {:#x}", command))?;
+            response.extend_from_slice(&chunk);
+        }
+        Ok::<_, anyhow::Error>(response)
+    }
+    .await;
+    // Unsubscribe even when the command failed, so that the next one
+    // doesn't receive stale notifications. Its error is only worth
+    // reporting when the command itself succeeded.
+    let unsubscribed = characteristic.unsubscribe().await;
+    let response = response?;
+    unsubscribed?;
+
+    if response[0] != command {
+        return Err(anyhow!(
+            "Unexpected response to command {:#x}: {:?}",
+            command,
+            response
+        ));
+    }
+    Ok(response)
+}
+
+/// Ask the device for its battery voltage.
+pub async fn read_battery(client: &mut BLEClient, model: Model) -> Result<Battery> {
+    let response = request(client, model, &[MULTI_SENSOR], |response| {
+        response.len() >= MULTI_SENSOR_LEN
+    })
+    .await?;
+    let millivolts = u16::from_le_bytes([response[BATTERY_OFFSET], response[BATTERY_OFFSET + 1]]);
+    let battery = Battery::from_voltage(f64::from(millivolts) / 1000.0);
+    if battery.low {
+        warn!("Battery of {} is low: {:?}", model.name(), battery);
+    }
+    Ok(battery)
+}